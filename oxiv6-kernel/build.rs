@@ -14,6 +14,12 @@
    limitations under the License.
 */
 
+// The parser the kernel applies LOG with, so the two can't disagree about what is valid
+// Its host tests are behind `cfg(host)`, which only the kernel crate itself declares
+#[allow(dead_code, unexpected_cfgs)]
+#[path = "src/log_filter.rs"]
+mod log_filter;
+
 fn main() {
     use std::{env, fs, path::PathBuf};
 
@@ -21,7 +27,23 @@ fn main() {
     fs::write(&ld, LINKER).unwrap();
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-env-changed=LOG");
-    println!("cargo:rustc-link-arg=-T{}", ld.display());
+    let log = env::var("LOG").unwrap_or_else(|_| String::from("info"));
+    check_log_filters(&log);
+    println!("cargo:rustc-env=OXIV6_LOG={log}");
+    println!("cargo:rustc-check-cfg=cfg(host)");
+    // Host builds are only for running unit tests, which link as ordinary programs and run with std
+    if env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("riscv64") {
+        println!("cargo:rustc-link-arg=-T{}", ld.display());
+    } else {
+        println!("cargo:rustc-cfg=host");
+    }
+}
+
+/// Fail the build unless the kernel can apply `log` over its default filters, as it does at boot
+fn check_log_filters(log: &str) {
+    if let Err(error) = log_filter::LogFilters::new(log::LevelFilter::Info).apply(log) {
+        panic!("LOG has log filters the kernel can't apply, `{log}`: {error:?}");
+    }
 }
 
 const LINKER: &[u8] = b"
@@ -54,6 +76,14 @@ SECTIONS
     *(.rodata .rodata.*)
   }
 
+  /* filled in by xtask after linking, see symbols.rs */
+  .kernel_symbols : {
+    . = ALIGN(8);
+    KEEP(*(.kernel_symbols))
+    . = ALIGN(0x1000);
+    PROVIDE(erodata = .);
+  }
+
   .data : {
     . = ALIGN(16);
     *(.sdata .sdata.*) /* do not need to distinguish this from .data */
@@ -0,0 +1,212 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! Tracking of every live allocation through [`crate::kalloc::ALLOCATOR`], to find leaks
+//!
+//! Allocations are kept in a fixed size table, as the table itself can't allocate. Once it is full, further
+//! allocations are only counted
+
+use crate::backtrace::return_addresses;
+use crate::magazine::InterruptsOff;
+use crate::println::println;
+use crate::proc::{myproc, Proc};
+use crate::symbols::Locations;
+use alloc::alloc::Layout;
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+
+/// The most live allocations tracked at once
+const TRACE_CAPACITY: usize = 2048;
+/// How many return addresses make up a call site
+const SITE_DEPTH: usize = 4;
+/// The frames to leave out of call sites, for this module and the allocator itself
+const ALLOCATOR_FRAMES: usize = 2;
+
+static TRACE: Mutex<AllocationTable> = Mutex::new(AllocationTable {
+    allocations: [None; TRACE_CAPACITY],
+    untracked: 0,
+});
+
+/// A live allocation, and who made it
+#[derive(Debug, Clone, Copy)]
+struct TracedAllocation {
+    address: usize,
+    layout: Layout,
+    /// The return addresses of the allocator's callers, innermost first
+    call_site: [usize; SITE_DEPTH],
+    /// The process running when it was allocated, if any
+    pid: Option<usize>,
+}
+
+/// Live allocations in an open addressed hash table, keyed by address
+struct AllocationTable {
+    allocations: [Option<TracedAllocation>; TRACE_CAPACITY],
+    /// Live allocations that didn't fit in the table
+    untracked: usize,
+}
+
+impl AllocationTable {
+    fn home_slot(address: usize) -> usize {
+        (address / 16) % TRACE_CAPACITY
+    }
+
+    /// The slot holding the allocation at `address`, if it is tracked
+    fn find(&self, address: usize) -> Option<usize> {
+        let home = Self::home_slot(address);
+        (0..TRACE_CAPACITY)
+            .map(|probe| (home + probe) % TRACE_CAPACITY)
+            .map_while(|slot| Some(slot).zip(self.allocations[slot]))
+            .find_map(|(slot, allocation)| (allocation.address == address).then_some(slot))
+    }
+
+    fn insert(&mut self, allocation: TracedAllocation) {
+        let home = Self::home_slot(allocation.address);
+        let free_slot = (0..TRACE_CAPACITY)
+            .map(|probe| (home + probe) % TRACE_CAPACITY)
+            .find(|&slot| self.allocations[slot].is_none());
+        match free_slot {
+            Some(slot) => self.allocations[slot] = Some(allocation),
+            None => self.untracked += 1,
+        }
+    }
+
+    /// Remove the allocation at `address`, moving later allocations in its probe sequence back so that they
+    /// can still be found
+    fn remove(&mut self, address: usize) {
+        let Some(mut vacant) = self.find(address) else {
+            self.untracked = self.untracked.saturating_sub(1);
+            return;
+        };
+        self.allocations[vacant] = None;
+        let mut slot = vacant;
+        loop {
+            slot = (slot + 1) % TRACE_CAPACITY;
+            let Some(allocation) = self.allocations[slot] else {
+                return;
+            };
+            // Move it into the vacant slot unless its home slot is between that and where it is now
+            let probes_from_home =
+                (slot + TRACE_CAPACITY - Self::home_slot(allocation.address)) % TRACE_CAPACITY;
+            let probes_from_vacant = (slot + TRACE_CAPACITY - vacant) % TRACE_CAPACITY;
+            if probes_from_home >= probes_from_vacant {
+                self.allocations[vacant] = Some(allocation);
+                self.allocations[slot] = None;
+                vacant = slot;
+            }
+        }
+    }
+}
+
+/// Track the allocation of `layout` at `address`, made by the allocator's callers on behalf of the running
+/// process
+#[inline(never)]
+pub(crate) fn record(address: *mut u8, layout: Layout) {
+    let mut call_site = [0; SITE_DEPTH];
+    return_addresses(ALLOCATOR_FRAMES, &mut call_site);
+    let _interrupts_off = InterruptsOff::new();
+    let pid = myproc().and_then(Proc::try_pid);
+    TRACE.lock().insert(TracedAllocation {
+        address: address as usize,
+        layout,
+        call_site,
+        pid,
+    });
+}
+
+/// Stop tracking the allocation at `address`, which is being freed
+pub(crate) fn forget(address: *mut u8) {
+    let _interrupts_off = InterruptsOff::new();
+    TRACE.lock().remove(address as usize);
+}
+
+/// Note that the allocation at `address` was resized in place to `new_size`
+pub(crate) fn resize(address: *mut u8, new_size: usize) {
+    let _interrupts_off = InterruptsOff::new();
+    let mut trace = TRACE.lock();
+    if let Some(slot) = trace.find(address as usize) {
+        let allocation = trace.allocations[slot].as_mut().unwrap();
+        allocation.layout = Layout::from_size_align(new_size, allocation.layout.align())
+            .unwrap_or(allocation.layout);
+    }
+}
+
+/// A copy of the live allocations that `filter` accepts
+/// The copy is allocated before the table is locked, as allocating would need the lock too
+fn live_allocations(filter: impl Fn(&TracedAllocation) -> bool) -> (Vec<TracedAllocation>, usize) {
+    let mut live = Vec::with_capacity(TRACE_CAPACITY);
+    let _interrupts_off = InterruptsOff::new();
+    let trace = TRACE.lock();
+    live.extend(
+        trace
+            .allocations
+            .iter()
+            .flatten()
+            .filter(|&allocation| filter(allocation)),
+    );
+    (live, trace.untracked)
+}
+
+/// Print every live allocation, grouped by call site, largest total first
+pub(crate) fn dump() {
+    let (mut live, untracked) = live_allocations(|_| true);
+    live.sort_unstable_by_key(|allocation| allocation.call_site);
+    let mut sites: Vec<(&[TracedAllocation], usize)> = live
+        .chunk_by(|a, b| a.call_site == b.call_site)
+        .map(|site| {
+            let bytes = site.iter().map(|allocation| allocation.layout.size()).sum();
+            (site, bytes)
+        })
+        .collect();
+    sites.sort_unstable_by_key(|&(_, bytes)| core::cmp::Reverse(bytes));
+
+    println!(
+        "{} live allocations from {} call sites, and {} untracked",
+        live.len(),
+        sites.len(),
+        untracked
+    );
+    for (site, bytes) in sites {
+        println!(
+            "{bytes} bytes in {} allocations from {}",
+            site.len(),
+            Locations(&site[0].call_site)
+        );
+    }
+}
+
+/// Print every allocation still owned by the process `pid`, which is exiting
+pub(crate) fn report_leaks(pid: usize) {
+    let (leaks, _) = live_allocations(|allocation| allocation.pid == Some(pid));
+    if leaks.is_empty() {
+        return;
+    }
+    let bytes: usize = leaks
+        .iter()
+        .map(|allocation| allocation.layout.size())
+        .sum();
+    log::warn!(
+        "Process {pid} exited owning {} allocations of {bytes} bytes",
+        leaks.len()
+    );
+    for leak in &leaks {
+        println!(
+            "  0x{:x}: {:?} from {}",
+            leak.address,
+            leak.layout,
+            Locations(&leak.call_site)
+        );
+    }
+}
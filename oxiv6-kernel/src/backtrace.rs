@@ -0,0 +1,138 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! Backtraces from the frame pointer chain, which `.cargo/config.toml` forces on for every function
+
+use crate::println::println;
+use crate::symbols::Location;
+use core::arch::asm;
+use core::ops::Range;
+
+/// The most frames a printed backtrace shows
+const MAX_PRINTED_FRAMES: usize = 64;
+
+/// The return addresses up the frame pointer chain, innermost first, staying on the kernel stack it started on
+/// Every frame saves the return address just below the frame pointer, and the caller's frame pointer below that.
+/// The entry points clear the frame pointer, so the chain ends with a null one
+pub(crate) struct Frames {
+    frame_pointer: usize,
+    stack: Range<usize>,
+}
+
+impl Frames {
+    /// The frames from `frame_pointer` up, or none if it is not on a kernel stack
+    pub(crate) fn from_frame_pointer(frame_pointer: usize) -> Self {
+        // A frame pointer can be the very top of its stack, which is where the first frame's starts
+        let stack = stack_bounds(frame_pointer.wrapping_sub(1)).unwrap_or(0..0);
+        Self {
+            frame_pointer,
+            stack,
+        }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let frame_pointer = self.frame_pointer;
+        let frame_size = 2 * core::mem::size_of::<usize>();
+        if !frame_pointer.is_multiple_of(core::mem::size_of::<usize>())
+            || frame_pointer < self.stack.start + frame_size
+            || frame_pointer > self.stack.end
+        {
+            return None;
+        }
+        let (return_address, caller_frame_pointer) = unsafe {
+            let frame = frame_pointer as *const usize;
+            (*frame.sub(1), *frame.sub(2))
+        };
+        if return_address == 0 {
+            return None;
+        }
+        // Frames move up the stack, so anything else ends the chain after this frame
+        self.frame_pointer = if caller_frame_pointer > frame_pointer {
+            caller_frame_pointer
+        } else {
+            0
+        };
+        Some(return_address)
+    }
+}
+
+/// The frame pointer of the function this is used in. A macro rather than a function, so that it can't have its
+/// own frame
+macro_rules! frame_pointer {
+    () => {{
+        let frame_pointer: usize;
+        unsafe { asm!("mv {}, s0", out(reg) frame_pointer) };
+        frame_pointer
+    }};
+}
+
+/// Fill `addresses` with the return addresses of the calling functions, innermost first. The first is in the
+/// caller of this function. The first `skip` are left out, so that helpers can leave themselves out of the trace
+/// Returns how many were found, which is fewer than `addresses.len()` if the chain ended first
+#[allow(dead_code)]
+#[inline(never)]
+pub(crate) fn return_addresses(skip: usize, addresses: &mut [usize]) -> usize {
+    let mut count = 0;
+    for (slot, return_address) in addresses
+        .iter_mut()
+        .zip(Frames::from_frame_pointer(frame_pointer!()).skip(skip))
+    {
+        *slot = return_address;
+        count += 1;
+    }
+    count
+}
+
+/// Print the return addresses of the calling functions, starting with this function's caller
+#[inline(never)]
+pub(crate) fn print_backtrace() {
+    print_frames(Frames::from_frame_pointer(frame_pointer!()));
+}
+
+/// Print the return addresses in `frames`, one per line and numbered from the innermost
+pub(crate) fn print_frames(frames: impl Iterator<Item = usize>) {
+    println!("backtrace:");
+    for (depth, return_address) in frames.take(MAX_PRINTED_FRAMES).enumerate() {
+        println!("  {depth:>2}: {}", Location(return_address));
+    }
+}
+
+/// The bounds of the kernel stack holding `address`: a hart's boot stack or trap stack, or a process's kernel stack
+#[cfg(not(host))]
+fn stack_bounds(address: usize) -> Option<Range<usize>> {
+    let hart_stacks = [
+        core::ptr::addr_of!(crate::STACK_0) as usize,
+        core::ptr::addr_of!(crate::trap::TRAP_STACKS) as usize,
+    ];
+    hart_stacks
+        .into_iter()
+        .find_map(|stacks| {
+            let hart = address.checked_sub(stacks)? / crate::STACK_SIZE;
+            let stack = stacks + hart * crate::STACK_SIZE;
+            (hart < crate::MAX_HART_COUNT).then_some(stack..stack + crate::STACK_SIZE)
+        })
+        .or_else(|| crate::proc::kstack_bounds(address))
+}
+
+/// Host tests have no kernel stacks to walk
+#[cfg(host)]
+fn stack_bounds(_address: usize) -> Option<Range<usize>> {
+    None
+}
@@ -0,0 +1,94 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::log_filter::LogFilters;
+use crate::vm::PagingMode;
+use log::{warn, LevelFilter};
+
+/// The parameters given to oxiv6 in `/chosen/bootargs`, such as with QEMU's `-append`
+/// Arguments are separated by whitespace, and are either `key=value` or a bare `key`
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct BootArguments {
+    /// `loglevel=`, log filters such as `debug` or `info,kalloc=debug,vm=trace`, applied over those from the
+    /// `LOG` the kernel was built with. Levels are names, or numbers from 0 (off) to 5 (trace). Filters that would
+    /// give more targets their own level than the kernel has room for, counting `LOG`'s, are ignored at boot
+    pub(crate) log_filters: Option<&'static str>,
+    /// `init=`, the path of the first user program
+    pub(crate) init: Option<&'static str>,
+    /// `root=`, the device to mount as the root file system
+    pub(crate) root: Option<&'static str>,
+    /// `maxcpus=`, the most harts to use
+    pub(crate) max_cpus: Option<usize>,
+    /// `mem=`, the most physical memory to use, in bytes, with an optional `K`, `M` or `G` suffix
+    pub(crate) mem: Option<usize>,
+    /// `paging=`, one of `sv39`, `sv48` or `sv57`
+    pub(crate) paging: Option<PagingMode>,
+}
+
+impl BootArguments {
+    /// Parse the arguments in `bootargs`, warning about and skipping any that are unknown or malformed
+    pub(crate) fn parse(bootargs: &'static str) -> Self {
+        let mut arguments = Self::default();
+        for argument in bootargs.split_whitespace() {
+            let (key, value) = argument.split_once('=').unwrap_or((argument, ""));
+            let parsed = match key {
+                "loglevel" => LogFilters::new(LevelFilter::Off)
+                    .apply(value)
+                    .ok()
+                    .map(|()| arguments.log_filters = Some(value)),
+                "init" => non_empty(value).map(|init| arguments.init = Some(init)),
+                "root" => non_empty(value).map(|root| arguments.root = Some(root)),
+                "maxcpus" => value
+                    .parse()
+                    .ok()
+                    .filter(|&max_cpus| max_cpus > 0)
+                    .map(|max_cpus| arguments.max_cpus = Some(max_cpus)),
+                "mem" => parse_size(value).map(|mem| arguments.mem = Some(mem)),
+                "paging" => value
+                    .parse()
+                    .ok()
+                    .map(|paging| arguments.paging = Some(paging)),
+                _ => {
+                    warn!("Ignoring unknown boot argument {}", argument);
+                    continue;
+                }
+            };
+            if parsed.is_none() {
+                warn!("Ignoring malformed boot argument {}", argument);
+            }
+        }
+        arguments
+    }
+}
+
+fn non_empty(value: &'static str) -> Option<&'static str> {
+    (!value.is_empty()).then_some(value)
+}
+
+/// Parse a number of bytes, which may end in `K`, `M` or `G`
+fn parse_size(value: &str) -> Option<usize> {
+    let (number, shift) = match value.as_bytes().last()? {
+        b'k' | b'K' => (&value[..value.len() - 1], 10),
+        b'm' | b'M' => (&value[..value.len() - 1], 20),
+        b'g' | b'G' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+        .filter(|&size| size > 0)
+}
@@ -14,4 +14,7 @@
    limitations under the License.
 */
 
+pub(crate) mod bootargs;
+#[allow(dead_code)]
+pub(crate) mod registry;
 pub(crate) mod spec;
@@ -0,0 +1,224 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::dev::spec::{get_fdt, PhysicalRange};
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use fdt::node::{FdtNode, NodeProperty};
+use log::{info, warn};
+use spin::{mutex::Mutex, once::Once};
+
+/// Every device in the FDT, indexed by each of its `compatible` strings
+static REGISTRY: Once<DeviceRegistry> = Once::new();
+/// The drivers that have been registered, in the order they were registered
+static DRIVERS: Mutex<Vec<&'static Driver>> = Mutex::new(Vec::new());
+
+/// A node in the FDT with a `compatible` property, which a driver can bind to
+pub(crate) struct Device {
+    node: FdtNode<'static, 'static>,
+    /// The phandle in the `interrupt-parent` property of this node, or of its nearest ancestor with one
+    interrupt_parent: Option<usize>,
+    bound: AtomicBool,
+}
+
+impl Device {
+    /// The name of the node, including its unit address
+    pub(crate) fn name(&self) -> &'static str {
+        self.node.name
+    }
+
+    /// The node this device was found at, for properties without an accessor here
+    pub(crate) fn node(&self) -> FdtNode<'static, 'static> {
+        self.node
+    }
+
+    /// The `compatible` strings of this device, from most to least specific
+    pub(crate) fn compatible(&self) -> impl Iterator<Item = &'static str> {
+        self.node
+            .compatible()
+            .into_iter()
+            .flat_map(fdt::standard_nodes::Compatible::all)
+    }
+
+    /// The physical ranges in the `reg` property
+    pub(crate) fn reg(&self) -> impl Iterator<Item = PhysicalRange> {
+        self.node
+            .reg()
+            .into_iter()
+            .flatten()
+            .map(|region| PhysicalRange {
+                start: region.starting_address as usize,
+                end: region.starting_address as usize + region.size.unwrap_or(0),
+            })
+    }
+
+    /// The interrupts in the `interrupts` property
+    pub(crate) fn interrupts(&self) -> impl Iterator<Item = usize> {
+        self.node.interrupts().into_iter().flatten()
+    }
+
+    /// The `phandle` other nodes refer to this one by, if it has one
+    fn phandle(&self) -> Option<usize> {
+        self.node
+            .property("phandle")
+            .or_else(|| self.node.property("linux,phandle"))
+            .and_then(NodeProperty::as_usize)
+    }
+
+    /// The device whose phandle is in the `interrupt-parent` property of this node, which is inherited from the
+    /// nearest ancestor with one if the node has none. Node names need not be unique, so the parent is found by
+    /// phandle
+    pub(crate) fn interrupt_parent(&self) -> Option<&'static Device> {
+        let phandle = self.interrupt_parent?;
+        REGISTRY
+            .get()?
+            .devices
+            .iter()
+            .copied()
+            .find(|device| device.phandle() == Some(phandle))
+    }
+
+    /// Is a driver bound to this device?
+    pub(crate) fn is_bound(&self) -> bool {
+        self.bound.load(Ordering::Acquire)
+    }
+}
+
+impl core::fmt::Debug for Device {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Device")
+            .field("name", &self.name())
+            .field("bound", &self.is_bound())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A driver, probed against every device that has one of its `compatible` strings
+#[derive(Debug)]
+pub(crate) struct Driver {
+    pub(crate) name: &'static str,
+    pub(crate) compatible: &'static [&'static str],
+    /// Set up `device`, which the driver is bound to if this succeeds
+    pub(crate) probe: fn(&'static Device) -> Result<(), ProbeError>,
+}
+
+/// Why a driver could not bind to a device
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ProbeError {
+    MissingProperty(&'static str),
+    Unsupported,
+}
+
+struct DeviceRegistry {
+    devices: Vec<&'static Device>,
+    by_compatible: BTreeMap<&'static str, Vec<&'static Device>>,
+}
+
+/// Index every node of the kernel's copy of the FDT that has a `compatible` property, then probe the drivers
+/// registered so far against them
+/// Must be called after the allocator is initialized
+pub(crate) fn init() {
+    REGISTRY.call_once(|| {
+        let mut registry = DeviceRegistry {
+            devices: Vec::new(),
+            by_compatible: BTreeMap::new(),
+        };
+        if let Some(root) = get_fdt().find_node("/") {
+            index_nodes(&mut registry, root, None);
+        }
+        info!("Found {} devices in the FDT", registry.devices.len());
+        registry
+    });
+    let drivers = DRIVERS.lock().clone();
+    for driver in drivers {
+        probe_driver(driver);
+    }
+}
+
+/// Index `node` and every node below it that has a `compatible` property, with `interrupt_parent` the phandle of
+/// the interrupt parent `node` inherits if it names none itself
+fn index_nodes(
+    registry: &mut DeviceRegistry,
+    node: FdtNode<'static, 'static>,
+    interrupt_parent: Option<usize>,
+) {
+    let interrupt_parent = node
+        .property("interrupt-parent")
+        .and_then(NodeProperty::as_usize)
+        .or(interrupt_parent);
+    if node.compatible().is_some() {
+        // Devices live as long as the kernel, so drivers can keep hold of them
+        let device: &'static Device = Box::leak(Box::new(Device {
+            node,
+            interrupt_parent,
+            bound: AtomicBool::new(false),
+        }));
+        for compatible in device.compatible() {
+            registry
+                .by_compatible
+                .entry(compatible)
+                .or_default()
+                .push(device);
+        }
+        registry.devices.push(device);
+    }
+    for child in node.children() {
+        index_nodes(registry, child, interrupt_parent);
+    }
+}
+
+/// The devices with `compatible` among their `compatible` strings
+pub(crate) fn find_compatible(compatible: &str) -> &'static [&'static Device] {
+    REGISTRY
+        .get()
+        .and_then(|registry| registry.by_compatible.get(compatible))
+        .map_or(&[], Vec::as_slice)
+}
+
+/// Register `driver`, probing it straight away if the devices have already been indexed
+pub(crate) fn register_driver(driver: &'static Driver) {
+    DRIVERS.lock().push(driver);
+    if REGISTRY.is_completed() {
+        probe_driver(driver);
+    }
+}
+
+/// Probe `driver` against every unbound device that matches one of its `compatible` strings
+fn probe_driver(driver: &'static Driver) {
+    for compatible in driver.compatible {
+        for device in find_compatible(compatible) {
+            if device
+                .bound
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                continue;
+            }
+            match (driver.probe)(device) {
+                Ok(()) => info!("{}: bound to {}", driver.name, device.name()),
+                Err(error) => {
+                    device.bound.store(false, Ordering::Release);
+                    warn!(
+                        "{}: unable to bind to {}: {:?}",
+                        driver.name,
+                        device.name(),
+                        error
+                    );
+                }
+            }
+        }
+    }
+}
@@ -14,35 +14,202 @@
    limitations under the License.
 */
 
+use crate::dev::bootargs::BootArguments;
+use crate::proc::{KSTACK_PAGES, NPROC};
+use crate::vm::PAGE_SIZE;
+use crate::MAX_HART_COUNT;
+use log::warn;
 use spin::once::Once;
 
+static FDT: Once<fdt::Fdt<'static>> = Once::new();
+static mut FDT_BUFFER: FdtBuffer = FdtBuffer([0; MAX_FDT_SIZE]);
 static PHYSICAL_ADDRESS_STOP: Once<usize> = Once::new();
+static MEMORY_REGIONS: Once<PhysicalRanges<MAX_MEMORY_REGIONS>> = Once::new();
+static RESERVED_REGIONS: Once<PhysicalRanges<MAX_RESERVED_REGIONS>> = Once::new();
 static CPU_COUNT: Once<usize> = Once::new();
-const MAX_VA: usize = 1 << (9 + 9 + 9 + 12 - 1);
+static TIMEBASE_FREQUENCY: Once<usize> = Once::new();
+static BOOT_ARGUMENTS: Once<BootArguments> = Once::new();
+/// The largest FDT blob that can be copied into the kernel
+const MAX_FDT_SIZE: usize = 64 * 1024;
+/// The most `/memory` regions oxiv6 keeps track of
+const MAX_MEMORY_REGIONS: usize = 16;
+/// The most reserved regions oxiv6 keeps track of, from the device tree and the kernel itself
+const MAX_RESERVED_REGIONS: usize = 32;
 
-/// Loads data from the FDT pointed to at `fdt_address`
+/// Kernel owned storage for the FDT, so it can be parsed after the memory the bootloader left it in is reused
+#[repr(C, align(8))]
+struct FdtBuffer([u8; MAX_FDT_SIZE]);
+
+/// A range of physical memory, from `start` up to but not including `end`
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub(crate) struct PhysicalRange {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+impl PhysicalRange {
+    /// Does this range share any memory with `other`?
+    pub(crate) fn overlaps(&self, other: &PhysicalRange) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    /// Does this range hold `address`?
+    pub(crate) fn contains(&self, address: usize) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+}
+
+/// A list of at most `N` ranges, which can be filled in before there is an allocator
+#[derive(Debug)]
+struct PhysicalRanges<const N: usize> {
+    ranges: [PhysicalRange; N],
+    count: usize,
+}
+
+impl<const N: usize> PhysicalRanges<N> {
+    fn new() -> Self {
+        Self {
+            ranges: [PhysicalRange::default(); N],
+            count: 0,
+        }
+    }
+
+    /// Add `[start, end)` to the list, ignoring it if it is empty, or warning if the list is full
+    fn push(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        if self.count == N {
+            warn!(
+                "Ignoring physical range 0x{:x}-0x{:x}, too many ranges",
+                start, end
+            );
+            return;
+        }
+        self.ranges[self.count] = PhysicalRange { start, end };
+        self.count += 1;
+    }
+
+    fn as_slice(&self) -> &[PhysicalRange] {
+        &self.ranges[..self.count]
+    }
+}
+
+/// Copies the FDT pointed to at `fdt_address` into the kernel, and loads data from it
 /// # Safety
 /// Assumes that the `fdt_address` points to a valid fdt and that the memory is mapped correctly.
+/// Must only be called once, on the boot hart
 /// # Panics
-/// Panics  if the address or the data at the address is invalid
+/// Panics  if the address or the data at the address is invalid, or the FDT is larger than `MAX_FDT_SIZE`
 pub(crate) unsafe fn load_fdt(fdt_address: usize) {
-    let fdt = unsafe { fdt::Fdt::from_ptr(fdt_address as *const u8) }.expect("Unable to load fdt");
-    // The true size of physical memory, calculated from the FDT
-    let true_physical_stop = fdt
-        .memory()
-        .regions()
-        .map(|region| region.starting_address as usize + region.size.unwrap_or(0))
-        .max()
-        .expect("Unable to determine the memory size allocated to oxiv6");
-    // Get the CPU Count from the FDT. The max for this value for qemu's `virt` architecture is 8, but we allow for more memory to
-    // be used if less CPUs are allocated.
-    let cpu_count = *CPU_COUNT.call_once(|| fdt.cpus().count());
-    // Reserved pages for the Trampoline and Kernel stacks (2 for trampoline, and 2 per CPU (stack + guard page))
-    let reserved_pages = 4096 * (2 * cpu_count + 1);
-    // Set the `PHYSICAL_ADDRESS_STOP` to the minimum of the true amount of system RAM, and the maxiumum amount of
-    // physical RAM before Xv6 breaks this is about 256GiB, so this is probably unecessary, but just covering all
-    // the bases here
-    PHYSICAL_ADDRESS_STOP.call_once(|| core::cmp::min(true_physical_stop, MAX_VA - reserved_pages));
+    let fdt = FDT.call_once(|| {
+        let size = unsafe { fdt::Fdt::from_ptr(fdt_address as *const u8) }
+            .expect("Unable to load fdt")
+            .total_size();
+        assert!(
+            size <= MAX_FDT_SIZE,
+            "FDT of {size} bytes is too large to copy"
+        );
+        let buffer = unsafe { &mut (&mut (*core::ptr::addr_of_mut!(FDT_BUFFER)).0)[..size] };
+        buffer.copy_from_slice(unsafe {
+            core::slice::from_raw_parts(fdt_address as *const u8, size)
+        });
+        fdt::Fdt::new(buffer).expect("Unable to load copied fdt")
+    });
+    // How fast the `time` CSR counts, which every hart shares. Found first, so the rest of boot logs with timestamps
+    TIMEBASE_FREQUENCY.call_once(|| {
+        fdt.cpus()
+            .next()
+            .map_or(0, fdt::standard_nodes::Cpu::timebase_frequency)
+    });
+    // The kernel command line, which lives as long as the FDT copy does
+    let boot_arguments = BOOT_ARGUMENTS.call_once(|| {
+        fdt.chosen()
+            .bootargs()
+            .map(BootArguments::parse)
+            .unwrap_or_default()
+    });
+    // Get the CPU Count from the FDT, capped at `MAX_HART_COUNT` and any `maxcpus=`. The max for this value for
+    // qemu's `virt` architecture is 8
+    CPU_COUNT.call_once(|| {
+        fdt.cpus()
+            .count()
+            .min(MAX_HART_COUNT)
+            .min(boot_arguments.max_cpus.unwrap_or(usize::MAX))
+    });
+    // Memory the allocator must never hand out: the kernel image, the FDT blob itself, the `/memreserve/` block,
+    // the `/reserved-memory` nodes and any initrd the bootloader left for us
+    RESERVED_REGIONS.call_once(|| {
+        let mut reserved_regions = PhysicalRanges::new();
+        reserved_regions.push(crate::_start as usize, crate::end as usize);
+        reserved_regions.push(fdt_address, fdt_address + fdt.total_size());
+        for reservation in fdt.memory_reservations() {
+            let start = reservation.address() as usize;
+            reserved_regions.push(start, start + reservation.size());
+        }
+        for region in fdt
+            .find_node("/reserved-memory")
+            .into_iter()
+            .flat_map(fdt::node::FdtNode::children)
+            .filter_map(fdt::node::FdtNode::reg)
+            .flatten()
+        {
+            let start = region.starting_address as usize;
+            reserved_regions.push(start, start + region.size.unwrap_or(0));
+        }
+        if let Some(chosen) = fdt.find_node("/chosen") {
+            if let (Some(start), Some(end)) = (
+                chosen
+                    .property("linux,initrd-start")
+                    .and_then(fdt::node::NodeProperty::as_usize),
+                chosen
+                    .property("linux,initrd-end")
+                    .and_then(fdt::node::NodeProperty::as_usize),
+            ) {
+                reserved_regions.push(start, end);
+            }
+        }
+        reserved_regions
+    });
+}
+
+/// Loads the regions of physical memory from the FDT, cut short where the kernel could no longer map them
+/// Must run once the paging mode has been chosen, as it decides how much memory can be mapped
+pub(crate) fn load_memory_regions() {
+    let fdt = get_fdt();
+    let boot_arguments = get_boot_arguments();
+    // Reserved pages for the Trampoline and the per-process kernel stacks (1 for the trampoline, and each stack plus its guard page)
+    let reserved_pages = PAGE_SIZE * (1 + NPROC * (KSTACK_PAGES + 1));
+    // Physical memory is mapped at the same addresses, so it must end below the pages reserved at the top of
+    // the address space. That is about 256GiB with Sv39 and far more with larger modes. Regions are also cut
+    // short once `mem=` bytes of memory have been found
+    let max_address = crate::vm::paging_mode().max_virtual_address() - reserved_pages;
+    let memory_regions = MEMORY_REGIONS.call_once(|| {
+        let mut memory_regions = PhysicalRanges::new();
+        let mut memory_left = boot_arguments.mem.unwrap_or(usize::MAX);
+        for region in fdt.memory().regions() {
+            let start = region.starting_address as usize;
+            let size = core::cmp::min(region.size.unwrap_or(0), memory_left);
+            memory_regions.push(start, core::cmp::min(start + size, max_address));
+            memory_left -= size;
+        }
+        memory_regions
+    });
+    // Set the `PHYSICAL_ADDRESS_STOP` to the end of the highest memory region
+    PHYSICAL_ADDRESS_STOP.call_once(|| {
+        memory_regions
+            .as_slice()
+            .iter()
+            .map(|region| region.end)
+            .max()
+            .expect("Unable to determine the memory size allocated to oxiv6")
+    });
+}
+
+/// The kernel's copy of the FDT
+#[inline]
+pub(crate) fn get_fdt() -> &'static fdt::Fdt<'static> {
+    FDT.wait()
 }
 
 #[inline]
@@ -50,7 +217,34 @@ pub(crate) fn get_cpu_count() -> usize {
     *CPU_COUNT.wait()
 }
 
+/// The ticks per second of the `time` CSR, if the FDT has been loaded
+#[inline]
+pub(crate) fn get_timebase_frequency() -> Option<usize> {
+    TIMEBASE_FREQUENCY
+        .get()
+        .copied()
+        .filter(|&frequency| frequency > 0)
+}
+
+/// The arguments parsed from `/chosen/bootargs`
+#[inline]
+pub(crate) fn get_boot_arguments() -> &'static BootArguments {
+    BOOT_ARGUMENTS.wait()
+}
+
 #[inline]
 pub(crate) fn get_physical_memory_size() -> usize {
     *PHYSICAL_ADDRESS_STOP.wait()
 }
+
+/// The regions of physical RAM described by the FDT, which may not be contiguous
+#[inline]
+pub(crate) fn get_memory_regions() -> &'static [PhysicalRange] {
+    MEMORY_REGIONS.wait().as_slice()
+}
+
+/// The regions of physical memory that must not be allocated, which may overlap each other
+#[inline]
+pub(crate) fn get_reserved_regions() -> &'static [PhysicalRange] {
+    RESERVED_REGIONS.wait().as_slice()
+}
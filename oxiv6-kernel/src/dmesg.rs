@@ -0,0 +1,248 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! The kernel log: every log record that is printed is also kept here, with when and on which hart it was logged,
+//! until newer records overwrite it. User space reads it with the `dmesg` system call, and a panic dumps it.
+//!
+//! Records can be logged from anywhere, including interrupt and trap handlers, so the ring is locked with interrupts
+//! off, and a hart that finds the ring already locked by itself drops its record rather than waiting on itself.
+
+use crate::magazine::InterruptsOff;
+use crate::println::{print, println};
+use crate::proc::cpuid;
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// How many bytes of records are kept
+const DMESG_SIZE: usize = 16 * 1024;
+/// The owner of an unlocked ring
+const NO_OWNER: usize = usize::MAX;
+/// How long a panic waits for another hart to unlock the ring before giving up on dumping it
+const PANIC_SPINS: usize = 1 << 20;
+
+static DMESG: Dmesg = Dmesg {
+    owner: AtomicUsize::new(NO_OWNER),
+    ring: UnsafeCell::new(LogRing::new()),
+};
+
+/// The newest `N` bytes of text written to the log, as lines
+struct LogRing<const N: usize> {
+    bytes: [u8; N],
+    /// Every byte ever written, so positions in the log never go backwards
+    written: usize,
+}
+
+/// The kernel log, and the hart holding it
+struct Dmesg {
+    owner: AtomicUsize,
+    ring: UnsafeCell<LogRing<DMESG_SIZE>>,
+}
+
+// The ring is only reached through `Dmesg::lock`, which gives it to one hart at a time
+unsafe impl Sync for Dmesg {}
+
+impl<const N: usize> LogRing<N> {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; N],
+            written: 0,
+        }
+    }
+
+    /// The position of the oldest whole line still kept. Once the ring is full, the newest `N - 1` bytes are kept,
+    /// along with the byte before them, which the next byte overwrites, to tell whether they start a line
+    fn start(&self) -> usize {
+        if self.written < N {
+            return 0;
+        }
+        (self.written - N..self.written)
+            .find(|&position| self.bytes[position % N] == b'\n')
+            .map_or(self.written, |newline| newline + 1)
+    }
+
+    /// Copy the log from `position` into `buffer`, returning how many bytes were copied and moving `position` past
+    /// them. Positions that have been overwritten skip ahead to the oldest line kept
+    fn read(&self, position: &mut usize, buffer: &mut [u8]) -> usize {
+        *position = (*position).max(self.start()).min(self.written);
+        let length = buffer.len().min(self.written - *position);
+        for (index, byte) in buffer[..length].iter_mut().enumerate() {
+            *byte = self.bytes[(*position + index) % N];
+        }
+        *position += length;
+        length
+    }
+}
+
+impl<const N: usize> Write for LogRing<N> {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        for &byte in string.as_bytes() {
+            self.bytes[self.written % N] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Dmesg {
+    /// Run `f` with the ring locked, with interrupts off. Gives up if this hart already holds the lock, such as when
+    /// a trap or panic interrupted it, or once `spins` have passed without it
+    fn lock<T>(
+        &self,
+        spins: Option<usize>,
+        f: impl FnOnce(&mut LogRing<DMESG_SIZE>) -> T,
+    ) -> Option<T> {
+        let _interrupts_off = InterruptsOff::new();
+        let hart = cpuid();
+        let mut spun = 0;
+        while let Err(owner) =
+            self.owner
+                .compare_exchange_weak(NO_OWNER, hart, Ordering::Acquire, Ordering::Relaxed)
+        {
+            if owner == hart || spins.is_some_and(|spins| spun >= spins) {
+                return None;
+            }
+            spun += 1;
+            core::hint::spin_loop();
+        }
+        let result = f(unsafe { &mut *self.ring.get() });
+        self.owner.store(NO_OWNER, Ordering::Release);
+        Some(result)
+    }
+}
+
+/// Keep `record` in the kernel log, as `[seconds.microseconds] hart level target: message`
+pub(crate) fn record(record: &log::Record) {
+    let (seconds, microseconds) = uptime();
+    let hart = cpuid();
+    // Dropped if this hart was already logging, so there is nothing to do when it fails
+    DMESG.lock(None, |ring| {
+        writeln!(
+            ring,
+            "[{seconds:>5}.{microseconds:06}] {hart} {:<5} {}: {}",
+            record.level(),
+            record.target(),
+            record.args()
+        )
+    });
+}
+
+/// Copy the kernel log, oldest first, to `buffer` from `position`, moving `position` past what was copied
+/// Returns how many bytes were copied, which is 0 at the end of the log
+pub(crate) fn read(position: &mut usize, buffer: &mut [u8]) -> usize {
+    DMESG
+        .lock(None, |ring| ring.read(position, buffer))
+        .unwrap_or(0)
+}
+
+/// Print the kernel log, for a panic. Another hart may hold the log, or this one may have panicked while holding it,
+/// so this gives up rather than waiting forever
+pub(crate) fn dump() {
+    println!("dmesg:");
+    let mut position = 0;
+    let mut buffer = [0; 128];
+    loop {
+        let Some(length) = DMESG.lock(Some(PANIC_SPINS), |ring| {
+            ring.read(&mut position, &mut buffer)
+        }) else {
+            println!("dmesg: the log is busy, giving up");
+            return;
+        };
+        if length == 0 {
+            return;
+        }
+        // A character may be split between reads, so it is printed by the next one
+        let text = match core::str::from_utf8(&buffer[..length]) {
+            Ok(text) => text,
+            Err(error) if error.valid_up_to() > 0 => {
+                position -= length - error.valid_up_to();
+                core::str::from_utf8(&buffer[..error.valid_up_to()]).unwrap()
+            }
+            Err(_) => "?",
+        };
+        print!("{}", text);
+    }
+}
+
+/// How long the harts have been running, in seconds and microseconds, or zero before the timebase is known
+#[cfg(not(host))]
+fn uptime() -> (usize, usize) {
+    let Some(frequency) = crate::dev::spec::get_timebase_frequency() else {
+        return (0, 0);
+    };
+    let ticks = riscv::register::time::read();
+    (ticks / frequency, ticks % frequency * 1_000_000 / frequency)
+}
+
+/// Host tests have no clock to read
+#[cfg(host)]
+fn uptime() -> (usize, usize) {
+    (0, 0)
+}
+
+#[cfg(all(test, host))]
+mod tests {
+    use super::*;
+
+    fn read_all<const N: usize>(ring: &LogRing<N>) -> std::string::String {
+        let mut position = 0;
+        let mut buffer = [0; 7];
+        let mut text = std::string::String::new();
+        loop {
+            let length = ring.read(&mut position, &mut buffer);
+            if length == 0 {
+                return text;
+            }
+            text.push_str(core::str::from_utf8(&buffer[..length]).unwrap());
+        }
+    }
+
+    #[test]
+    fn keeps_the_newest_whole_lines() {
+        let mut ring = LogRing::<32>::new();
+        ring.write_str("first line\nsecond line\n").unwrap();
+        assert_eq!(read_all(&ring), "first line\nsecond line\n");
+        // 34 bytes have been written, so the first line is partly overwritten and skipped
+        ring.write_str("third line\n").unwrap();
+        assert_eq!(read_all(&ring), "second line\nthird line\n");
+    }
+
+    #[test]
+    fn overwritten_positions_skip_ahead() {
+        let mut ring = LogRing::<16>::new();
+        ring.write_str("one\ntwo\n").unwrap();
+        let mut position = 0;
+        let mut buffer = [0; 4];
+        assert_eq!(ring.read(&mut position, &mut buffer), 4);
+        assert_eq!(&buffer, b"one\n");
+        ring.write_str("three\nfourteen\n").unwrap();
+        // `two` has been overwritten since, so the next read starts at `three`
+        assert_eq!(ring.read(&mut position, &mut buffer), 4);
+        assert_eq!(&buffer, b"thre");
+        assert_eq!(position, 12);
+    }
+
+    #[test]
+    fn the_lock_is_not_taken_twice_by_one_hart() {
+        let dmesg = Dmesg {
+            owner: AtomicUsize::new(NO_OWNER),
+            ring: UnsafeCell::new(LogRing::new()),
+        };
+        let nested = dmesg.lock(None, |_| dmesg.lock(None, |_| ()));
+        assert_eq!(nested, Some(None));
+        assert_eq!(dmesg.lock(Some(0), |_| ()), Some(()));
+    }
+}
@@ -0,0 +1,45 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use core::fmt::Debug;
+
+/// An open file that can back a memory mapping
+pub(crate) trait File: Debug + Send + Sync {
+    /// Read up to `buffer.len()` bytes from the file, starting at `offset`
+    /// Returns the number of bytes read, bytes past the end of the file are left untouched
+    fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, FileError>;
+
+    /// Write `buffer` into the file, starting at `offset`
+    /// Returns the number of bytes written
+    fn write_at(&self, offset: usize, buffer: &[u8]) -> Result<usize, FileError>;
+
+    /// The current length of the file in bytes
+    fn size(&self) -> usize;
+
+    /// Can the file be read from?
+    fn readable(&self) -> bool;
+
+    /// Can the file be written to?
+    fn writeable(&self) -> bool;
+}
+
+#[derive(Debug)]
+pub(crate) enum FileError {
+    #[allow(dead_code)]
+    InvalidOffset,
+    #[allow(dead_code)]
+    IoError,
+}
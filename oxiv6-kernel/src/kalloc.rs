@@ -14,381 +14,666 @@
    limitations under the License.
 */
 
-use crate::dev::spec::get_physical_memory_size;
+#[cfg(feature = "alloc-trace")]
+use crate::alloc_trace;
+use crate::dev::spec::{get_memory_regions, get_reserved_regions, PhysicalRange};
+#[cfg(feature = "kasan")]
+use crate::kasan::{self, Poison, SHADOW_BYTES_PER_PAGE};
+use crate::magazine::{HartCache, InterruptsOff};
+use crate::proc::cpuid;
+use crate::slab::{SlabCache, SlabCacheStatistics, SLAB_CACHE_COUNT};
 use crate::vm::{PAGE_SIZE, PGROUNDDOWN, PGROUNDUP};
+use crate::MAX_HART_COUNT;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::cell::Cell;
 use core::ptr::{self, null_mut, NonNull};
+use core::sync::atomic::{AtomicU8, Ordering};
 use log::debug;
-use spin::mutex::{Mutex, MutexGuard};
+use spin::{mutex::Mutex, once::Once};
 
+/// The largest order of block the page allocator hands out, `2^MAX_ORDER` pages (4 MiB)
+pub(crate) const MAX_ORDER: usize = 10;
+/// Marks a page in `free_orders` that does not start a free block
+const NOT_FREE: u8 = u8::MAX;
+/// Without the sanitizer, no shadow memory is kept past the allocator's metadata
+#[cfg(not(feature = "kasan"))]
+const SHADOW_BYTES_PER_PAGE: usize = 0;
+
+/// The physical memory the page allocator manages: the memory regions to hand out, less the reserved ones
+/// The kernel passes the regions in the FDT, host tests pass an arena on the heap
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PhysicalMemoryMap<'a> {
+    pub(crate) regions: &'a [PhysicalRange],
+    pub(crate) reserved: &'a [PhysicalRange],
+}
+
+/// A free block, kept in the list for its order
 #[repr(C)]
 struct Run {
     pub next: Cell<Option<NonNull<Run>>>,
+    pub prev: Cell<Option<NonNull<Run>>>,
 }
 
-#[repr(C, align(16))]
-struct TinyHeader {
-    next: Cell<Option<NonNull<TinyHeader>>>,
-    size: usize,
+/// The free blocks of the buddy allocator. A block of order `n` is `2^n` pages, aligned to its own size,
+/// and its buddy is the block of the same order it was split from
+struct FreeLists<'a> {
+    heads: [Option<NonNull<Run>>; MAX_ORDER + 1],
+    /// For each page, the order of the free block starting there, or `NOT_FREE`
+    free_orders: Option<&'a mut [u8]>,
+    /// The memory the pages are in, to find their index in `free_orders`
+    memory_map: Option<PhysicalMemoryMap<'a>>,
 }
 
 pub(crate) struct KernelPageAllocator<'a> {
-    freelist: Mutex<Cell<Option<NonNull<Run>>>>,
-    page_refcounts: Mutex<Cell<Option<&'a mut [u8]>>>,
+    free_lists: Mutex<FreeLists<'a>>,
+    page_refcounts: Once<&'a [AtomicU8]>,
+    memory_map: Once<PhysicalMemoryMap<'a>>,
 }
 
 pub(crate) struct KernelAllocator<'a> {
     page_allocator: KernelPageAllocator<'a>,
-    tiny_page_list: Mutex<Cell<Option<NonNull<TinyHeader>>>>,
+    slab_caches: [SlabCache; SLAB_CACHE_COUNT],
+    /// Each hart's magazines of single pages and slab objects, in front of the global pools
+    hart_caches: [Mutex<HartCache>; MAX_HART_COUNT],
 }
 
-#[global_allocator]
-pub(crate) static ALLOCATOR: KernelAllocator = KernelAllocator {
-    page_allocator: KernelPageAllocator {
-        freelist: Mutex::new(Cell::new(None)),
-        page_refcounts: Mutex::new(Cell::new(None)),
-    },
-    tiny_page_list: Mutex::new(Cell::new(None)),
-};
+/// An allocation failed because memory ran out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct OutOfMemory;
+
+/// How much memory is free, in the page allocator and in each slab cache
+#[derive(Debug)]
+pub(crate) struct MemoryStatistics {
+    pub(crate) free_page_bytes: usize,
+    /// Bytes free in the per-hart magazines, which the page allocator and slab caches count as in use
+    pub(crate) magazine_bytes: usize,
+    pub(crate) slab_caches: [SlabCacheStatistics; SLAB_CACHE_COUNT],
+}
+
+#[cfg_attr(not(host), global_allocator)]
+pub(crate) static ALLOCATOR: KernelAllocator = KernelAllocator::new();
 
 unsafe impl<'a> Sync for KernelPageAllocator<'a> {}
 unsafe impl<'a> Send for KernelPageAllocator<'a> {}
 unsafe impl<'a> Sync for KernelAllocator<'a> {}
 unsafe impl<'a> Send for KernelAllocator<'a> {}
 
-unsafe impl<'a> GlobalAlloc for KernelPageAllocator<'a> {
-    /// Allocates a page of physical memory
-    /// Ignores `layout`, except to check that the request is for no more than a page of memory
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size = layout.size();
-        let align = layout.align();
-
-        // Cannot allocate or align more than a page
-        if size > PAGE_SIZE || align > PAGE_SIZE {
-            return ptr::null_mut();
+impl PhysicalMemoryMap<'_> {
+    /// The memory regions and reserved regions described by the FDT
+    fn from_fdt() -> PhysicalMemoryMap<'static> {
+        PhysicalMemoryMap {
+            regions: get_memory_regions(),
+            reserved: get_reserved_regions(),
         }
+    }
 
-        let freelist = self.freelist.lock();
-        let return_cell = Cell::new(None);
-        return_cell.swap(&freelist);
-        if let Some(run_cell) = return_cell.get() {
-            freelist.swap(&unsafe { run_cell.as_ref() }.next);
-        }
-
-        match return_cell.get().map(NonNull::cast::<u8>) {
-            None => null_mut(),
-            Some(ptr) => {
-                let final_ptr = ptr.as_ptr();
-                {
-                    let page_refcounts = self.page_refcounts.lock();
-                    let refcount_data = page_refcounts.take().unwrap();
-                    // The index in the refcount data to update.
-                    let page_index = Self::convert_physical_to_index(final_ptr as usize);
-                    refcount_data[page_index] += 1;
-                    page_refcounts.set(Some(refcount_data));
-                }
-                core::mem::drop(freelist);
-                unsafe { ptr::write_bytes(final_ptr, 5, PAGE_SIZE) };
-                final_ptr
+    /// The number of whole pages across every memory region
+    fn page_count(&self) -> usize {
+        self.regions
+            .iter()
+            .map(|region| {
+                PGROUNDDOWN!(region.end).saturating_sub(PGROUNDUP!(region.start)) / PAGE_SIZE
+            })
+            .sum()
+    }
+
+    /// The index of the page holding `physical_address` in the reference counts, if it is in a memory region
+    /// The pages of each memory region are counted in turn
+    fn page_index(&self, physical_address: usize) -> Option<usize> {
+        let mut index = 0;
+        for region in self.regions {
+            let start = PGROUNDUP!(region.start);
+            let end = PGROUNDDOWN!(region.end);
+            if (start..end).contains(&physical_address) {
+                return Some(index + (physical_address - start) / PAGE_SIZE);
             }
+            index += end.saturating_sub(start) / PAGE_SIZE;
         }
+        None
     }
 
-    /// Deallocate a page allocated by this allocator
-    #[allow(clippy::cast_ptr_alignment)]
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        unsafe {
-            let size = layout.size();
-            let align = layout.align();
-            let ptr_int = ptr as usize;
-            if ptr_int % PAGE_SIZE != 0
-                || ptr_int < crate::end as usize
-                || ptr_int >= get_physical_memory_size()
-                || size > PAGE_SIZE
-                || align > PAGE_SIZE
-            {
-                panic!("KPA_dealloc: Out of bounds");
-            }
+    /// Is `address` in a memory region?
+    fn is_physical_memory(&self, address: usize) -> bool {
+        self.regions.iter().any(|region| region.contains(address))
+    }
 
-            // Lock any modifications to the freelist for the remainder of the execution
-            // We want to make sure that we don't deadlock, and that we don't change the refcount before deallocating
-            // We also want to have the same lock order as alloc
-            let freelist = self.freelist.lock();
-
-            let page_refcounts = self.page_refcounts.lock();
-            let refcount = {
-                let refcount_data = page_refcounts.take().unwrap();
-                // The index in the refcount data to update. Previous checks ensure this is in bounds
-                let page_index = Self::convert_physical_to_index(ptr_int);
-                // Panic if no references were loaned out to the Kernel
-                let mut refcount = refcount_data[page_index];
-                assert!(refcount != 0, "KPA_dealloc: No page references");
-                // Remove a reference to this page
-                refcount -= 1;
-                refcount_data[page_index] = refcount;
-                page_refcounts.set(Some(refcount_data));
-                refcount
-            };
-            core::mem::drop(page_refcounts);
+    /// Does `range` share any memory with a reserved region?
+    fn is_reserved(&self, range: &PhysicalRange) -> bool {
+        self.reserved
+            .iter()
+            .any(|reserved| reserved.overlaps(range))
+    }
+}
 
-            // Only actually deallocate if we have 0 references
-            if refcount == 0 {
-                ptr::write_bytes(ptr, 1, PAGE_SIZE);
-                let run_ref_option: Option<&'static mut Run> = ptr.cast::<Run>().as_mut();
-                if let Some(run_ref) = run_ref_option {
-                    run_ref.next = Cell::new(None);
-                    run_ref.next.swap(&freelist);
-                    let run_cell =
-                        Cell::new(Some(NonNull::new_unchecked(core::ptr::from_mut::<Run>(
-                            run_ref,
-                        ))));
-                    freelist.swap(&run_cell);
-                }
+impl FreeLists<'_> {
+    /// Add the free block at `block` to the list for `order`
+    /// # Safety
+    /// `block` must be a free block of `order` pages, not already in a list
+    unsafe fn push(&mut self, block: NonNull<Run>, order: usize) {
+        let run = unsafe { block.as_ptr().as_mut() }.unwrap();
+        run.next = Cell::new(self.heads[order]);
+        run.prev = Cell::new(None);
+        if let Some(next) = self.heads[order] {
+            unsafe { next.as_ref() }.prev.set(Some(block));
+        }
+        self.heads[order] = Some(block);
+        #[allow(clippy::cast_possible_truncation)]
+        self.set_free_order(block, order as u8);
+    }
+
+    /// Take the first free block from the list for `order`
+    fn pop(&mut self, order: usize) -> Option<NonNull<Run>> {
+        let block = self.heads[order]?;
+        unsafe { self.remove(block, order) };
+        Some(block)
+    }
+
+    /// Take `block` out of the list for `order`
+    /// # Safety
+    /// `block` must be in the list for `order`
+    unsafe fn remove(&mut self, block: NonNull<Run>, order: usize) {
+        let run = unsafe { block.as_ref() };
+        match run.prev.get() {
+            Some(prev) => unsafe { prev.as_ref() }.next.set(run.next.get()),
+            None => self.heads[order] = run.next.get(),
+        }
+        if let Some(next) = run.next.get() {
+            unsafe { next.as_ref() }.prev.set(run.prev.get());
+        }
+        self.set_free_order(block, NOT_FREE);
+    }
+
+    /// Is there a free block of `order` at `physical_address`?
+    #[allow(clippy::cast_possible_truncation)]
+    fn is_free_block(&self, physical_address: usize, order: usize) -> bool {
+        self.memory_map
+            .unwrap()
+            .page_index(physical_address)
+            .is_some_and(|index| self.free_orders.as_ref().unwrap()[index] == order as u8)
+    }
+
+    fn set_free_order(&mut self, block: NonNull<Run>, order: u8) {
+        let index = convert_physical_to_index(&self.memory_map.unwrap(), block.as_ptr() as usize);
+        self.free_orders.as_mut().unwrap()[index] = order;
+    }
+}
+
+unsafe impl<'a> GlobalAlloc for KernelPageAllocator<'a> {
+    /// Allocates a block of physical memory, of the smallest power of two pages that fits `layout`
+    /// The block is aligned to its own size, so alignments up to its size are met too
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(order) = Self::order_for(layout) else {
+            return ptr::null_mut();
+        };
+        let Some(block) = Self::take_block(&mut self.free_lists.lock(), order) else {
+            return null_mut();
+        };
+        let final_ptr = block.as_ptr().cast::<u8>();
+        self.take_reference(final_ptr);
+        unsafe { ptr::write_bytes(final_ptr, 5, PAGE_SIZE << order) };
+        final_ptr
+    }
+
+    /// Deallocate a block allocated by this allocator, merging it with its buddy for as long as the buddy is free
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let order = self.check_dealloc(ptr, layout);
+        // Only actually deallocate if we have 0 references
+        if self.drop_reference(ptr) {
+            unsafe {
+                ptr::write_bytes(ptr, 1, PAGE_SIZE << order);
+                Self::free_block(&mut self.free_lists.lock(), ptr, order);
             }
         }
     }
 }
 
-impl KernelPageAllocator<'_> {
-    pub fn init(&self, page_count: usize) {
-        debug!(
-            "Initializing allocator, writing bytes to {:x}",
-            crate::end as usize
+impl<'a> KernelPageAllocator<'a> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            free_lists: Mutex::new(FreeLists {
+                heads: [None; MAX_ORDER + 1],
+                free_orders: None,
+                memory_map: None,
+            }),
+            page_refcounts: Once::new(),
+            memory_map: Once::new(),
+        }
+    }
+
+    /// Set up the reference counts and free block orders for the pages of `memory_map` at `metadata_address`,
+    /// followed by the shadow memory with the `kasan` feature, then free every page of every memory region that
+    /// is neither reserved nor holding that metadata
+    /// # Panics
+    /// Panics if the metadata would overlap reserved memory
+    pub fn init(&self, memory_map: PhysicalMemoryMap<'a>, metadata_address: usize) {
+        debug!("Initializing allocator, writing bytes to {metadata_address:x}");
+        let memory_map = *self.memory_map.call_once(|| memory_map);
+        let page_count = memory_map.page_count();
+        let metadata_range = PhysicalRange {
+            start: metadata_address,
+            end: PGROUNDUP!(metadata_address + (2 + SHADOW_BYTES_PER_PAGE) * page_count),
+        };
+        assert!(
+            !memory_map.is_reserved(&metadata_range),
+            "KPA_init: Page reference counts overlap reserved memory"
         );
+        let free_orders_address = metadata_address + page_count;
         unsafe {
-            core::ptr::write_bytes(crate::end as *mut u8, 1, page_count);
+            core::ptr::write_bytes(metadata_address as *mut u8, 1, page_count);
+            core::ptr::write_bytes(free_orders_address as *mut u8, NOT_FREE, page_count);
         }
-        let refcount_cell = self.page_refcounts.lock();
-        refcount_cell.set(Some(unsafe {
-            core::slice::from_raw_parts_mut(crate::end as *mut u8, page_count)
-        }));
+        self.page_refcounts.call_once(|| unsafe {
+            core::slice::from_raw_parts(metadata_address as *const AtomicU8, page_count)
+        });
         debug!("Set Refcounts!");
-        core::mem::drop(refcount_cell);
-        debug!("Dropped Refcounts!");
+        #[cfg(feature = "kasan")]
+        kasan::init(free_orders_address + page_count, page_count);
+        {
+            let mut free_lists = self.free_lists.lock();
+            free_lists.free_orders = Some(unsafe {
+                core::slice::from_raw_parts_mut(free_orders_address as *mut u8, page_count)
+            });
+            free_lists.memory_map = Some(memory_map);
+        }
 
-        let mut ptr = PGROUNDUP!(crate::end as usize + page_count) as *mut u8;
         let layout = unsafe { Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE) };
 
         debug!("Deallocating pages");
-        let phystop = get_physical_memory_size();
-
-        while unsafe { ptr.byte_add(PAGE_SIZE) <= phystop as *mut u8 } {
-            unsafe {
-                self.dealloc(ptr, layout);
+        for region in memory_map.regions {
+            let mut ptr = PGROUNDUP!(region.start);
+            while ptr + PAGE_SIZE <= region.end {
+                let page = PhysicalRange {
+                    start: ptr,
+                    end: ptr + PAGE_SIZE,
+                };
+                if !page.overlaps(&metadata_range) && !memory_map.is_reserved(&page) {
+                    unsafe {
+                        self.dealloc(ptr as *mut u8, layout);
+                    }
+                    #[cfg(feature = "kasan")]
+                    kasan::poison(ptr, PAGE_SIZE, Poison::PageFree);
+                    debug!("Deallocated {:x}/{:x}", ptr, region.end);
+                }
+                ptr += PAGE_SIZE;
             }
-            debug!("Deallocated {:x}/{:x}", ptr as usize, phystop);
-            ptr = unsafe { ptr.byte_add(PAGE_SIZE) };
         }
         debug!("Deallocated memory");
     }
 
-    #[allow(dead_code)]
+    /// The order of the smallest block that holds `layout`, if there is one
+    fn order_for(layout: Layout) -> Option<usize> {
+        let pages = core::cmp::max(layout.size(), layout.align()).div_ceil(PAGE_SIZE);
+        let order = pages.max(1).next_power_of_two().trailing_zeros() as usize;
+        (order <= MAX_ORDER).then_some(order)
+    }
+
     pub(crate) fn pfree_count(&self) -> usize {
         let mut free_memory = 0usize;
-        let freelist = self.freelist.lock();
-        let mut optional_run_ref = freelist.get();
-        while optional_run_ref.is_some() {
-            free_memory += PAGE_SIZE;
-            optional_run_ref = optional_run_ref.and_then(|ptr| unsafe { ptr.as_ref() }.next.get());
+        let free_lists = self.free_lists.lock();
+        for (order, head) in free_lists.heads.iter().enumerate() {
+            let mut optional_run_ref = *head;
+            while optional_run_ref.is_some() {
+                free_memory += PAGE_SIZE << order;
+                optional_run_ref =
+                    optional_run_ref.and_then(|ptr| unsafe { ptr.as_ref() }.next.get());
+            }
         }
         free_memory
     }
 
-    #[inline]
-    fn convert_physical_to_index(physical_address: usize) -> usize {
-        PGROUNDDOWN!(physical_address - PGROUNDUP!(crate::end as usize)) / PAGE_SIZE
+    /// The index of the page holding `physical_address` in the reference counts, if it is in a memory region
+    /// that has been set up
+    pub(crate) fn physical_to_index(&self, physical_address: usize) -> Option<usize> {
+        self.memory_map.get()?.page_index(physical_address)
+    }
+
+    /// The memory this allocator manages
+    fn memory_map(&self) -> &PhysicalMemoryMap<'a> {
+        self.memory_map
+            .get()
+            .expect("KPA: Allocator used before initialization")
     }
 
     #[allow(dead_code)]
     pub fn in_place_copy(&self, physical_address: usize) {
-        let index = Self::convert_physical_to_index(physical_address);
-        let refcounts = self.page_refcounts.lock();
-        let refcount_data = refcounts.take().unwrap();
-        refcount_data[index] += 1;
-        refcounts.set(Some(refcount_data));
+        self.refcount(physical_address as *mut u8)
+            .fetch_add(1, Ordering::AcqRel);
     }
 
     #[allow(dead_code)]
     pub(crate) fn exactly_one_reference(&self, physical_address: usize) -> bool {
-        let index = Self::convert_physical_to_index(physical_address);
-        let reference_counts = self.page_refcounts.lock();
-        let reference_data = reference_counts.take().unwrap();
-        let is_exactly_one_reference = reference_data[index] == 1;
-        reference_counts.set(Some(reference_data));
-        is_exactly_one_reference
+        self.refcount(physical_address as *mut u8)
+            .load(Ordering::Acquire)
+            == 1
     }
-}
-
-unsafe impl GlobalAlloc for KernelAllocator<'_> {
-    #[allow(clippy::cast_ptr_alignment)]
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size = layout.size();
-        let align = layout.align();
 
-        // Pass off allocations greater or equal to a page to the page allocator
-        // Size will delegate to the page allocator if it is bigger than
-        if size >= (PAGE_SIZE - (2 * core::mem::size_of::<TinyHeader>())) || align >= PAGE_SIZE {
-            unsafe { self.page_allocator.alloc(layout) }
-        } else {
-            if align > Self::MAX_ALIGNMENT {
-                return ptr::null_mut();
+    /// Take up to `pages.len()` free pages into `pages` under one lock, without counting references to them
+    /// Returns how many were taken, which is fewer than asked for only if memory runs out
+    pub(crate) fn take_pages(&self, pages: &mut [*mut u8]) -> usize {
+        let mut free_lists = self.free_lists.lock();
+        for (count, page) in pages.iter_mut().enumerate() {
+            match Self::take_block(&mut free_lists, 0) {
+                Some(block) => *page = block.as_ptr().cast(),
+                None => return count,
             }
-            let size = (size + Self::MAX_ALIGNMENT - 1) & !(Self::MAX_ALIGNMENT - 1);
-            let tiny_list = self.tiny_page_list.lock();
-            if let Some(list) = tiny_list.get() {
-                let mut header = list;
-                let mut prev: Option<NonNull<TinyHeader>> = None;
-                let data = loop {
-                    let header_mut = unsafe { header.as_mut() };
-                    let header_size = header_mut.size;
-                    if header_size >= size {
-                        break Self::write_blocks(&mut prev, &tiny_list, header_mut, size);
-                    }
+        }
+        pages.len()
+    }
 
-                    if header_mut.next.get().is_none() {
-                        break ptr::null_mut();
-                    }
+    /// Give back every page in `pages` under one lock
+    /// # Safety
+    /// Every page must be from [`Self::take_pages`], and have no references left
+    pub(crate) unsafe fn return_pages(&self, pages: &[*mut u8]) {
+        let mut free_lists = self.free_lists.lock();
+        for &page in pages {
+            unsafe { Self::free_block(&mut free_lists, page, 0) };
+        }
+    }
 
-                    prev = Some(header);
-                    header = header_mut.next.get().unwrap();
-                };
-                if data.is_null() {
-                    let page_layout =
-                        unsafe { Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE) };
-                    let new_page = unsafe { self.page_allocator.alloc(page_layout) };
-                    if new_page.is_null() {
-                        new_page
-                    } else {
-                        let free_header =
-                            unsafe { new_page.add(size + core::mem::size_of::<TinyHeader>()) }
-                                .cast::<TinyHeader>();
-                        unsafe {
-                            *free_header = TinyHeader {
-                                next: Cell::new(tiny_list.get()),
-                                size: PAGE_SIZE - (size + 2 * core::mem::size_of::<TinyHeader>()),
-                            }
-                        };
-                        unsafe {
-                            *new_page.cast::<TinyHeader>() = TinyHeader {
-                                next: Cell::new(None),
-                                size,
-                            }
-                        }
-                        tiny_list.set(NonNull::new(free_header));
-                        unsafe { new_page.cast::<TinyHeader>().add(1) }.cast()
-                    }
-                } else {
-                    data
-                }
-            } else {
-                let page_layout =
-                    unsafe { Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE) };
-                let new_page = unsafe { self.page_allocator.alloc(page_layout) };
-                if new_page.is_null() {
-                    new_page
-                } else {
-                    let free_header =
-                        unsafe { new_page.add(size + core::mem::size_of::<TinyHeader>()) }
-                            .cast::<TinyHeader>();
-                    unsafe {
-                        *free_header = TinyHeader {
-                            next: Cell::new(None),
-                            size: PAGE_SIZE - (size + 2 * core::mem::size_of::<TinyHeader>()),
-                        }
-                    };
-                    unsafe {
-                        *new_page.cast::<TinyHeader>() = TinyHeader {
-                            next: Cell::new(None),
-                            size,
-                        }
-                    }
-                    tiny_list.set(NonNull::new(free_header));
-                    unsafe { new_page.cast::<TinyHeader>().offset(1) }.cast()
-                }
+    /// Check that `ptr` could be a block allocated for `layout`, returning the order of the block
+    /// # Panics
+    /// Panics if `ptr` is misaligned for its block, or is not in unreserved physical memory
+    pub(crate) fn check_dealloc(&self, ptr: *mut u8, layout: Layout) -> usize {
+        let ptr_int = ptr as usize;
+        let Some(order) =
+            Self::order_for(layout).filter(|&order| ptr_int.is_multiple_of(PAGE_SIZE << order))
+        else {
+            panic!("KPA_dealloc: Out of bounds");
+        };
+        let memory_map = self.memory_map();
+        if !memory_map.is_physical_memory(ptr_int)
+            || memory_map.is_reserved(&PhysicalRange {
+                start: ptr_int,
+                end: ptr_int + PAGE_SIZE,
+            })
+        {
+            panic!("KPA_dealloc: Out of bounds");
+        }
+        order
+    }
+
+    /// Count a reference to the block at `ptr`, which has just been handed out
+    pub(crate) fn take_reference(&self, ptr: *mut u8) {
+        self.refcount(ptr).fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Remove a reference to the block at `ptr`, returning whether it was the last one
+    /// # Panics
+    /// Panics if no references were loaned out to the Kernel
+    pub(crate) fn drop_reference(&self, ptr: *mut u8) -> bool {
+        let refcount = self.refcount(ptr).fetch_sub(1, Ordering::AcqRel);
+        assert!(refcount != 0, "KPA_dealloc: No page references");
+        refcount == 1
+    }
+
+    /// The reference count of the page at `ptr`. Blocks are counted by their first page
+    fn refcount(&self, ptr: *mut u8) -> &AtomicU8 {
+        &self
+            .page_refcounts
+            .get()
+            .expect("KPA: Allocator used before initialization")
+            [convert_physical_to_index(self.memory_map(), ptr as usize)]
+    }
+
+    /// Take a free block of `order`, splitting a larger one if there are none
+    fn take_block(free_lists: &mut FreeLists<'_>, order: usize) -> Option<NonNull<Run>> {
+        let (block, mut block_order) = (order..=MAX_ORDER).find_map(|block_order| {
+            free_lists
+                .pop(block_order)
+                .map(|block| (block, block_order))
+        })?;
+        // Split the block, freeing the upper half each time, until it is the size asked for
+        while block_order > order {
+            block_order -= 1;
+            let buddy = unsafe {
+                NonNull::new_unchecked(block.as_ptr().byte_add(PAGE_SIZE << block_order))
+            };
+            unsafe { free_lists.push(buddy, block_order) };
+        }
+        Some(block)
+    }
+
+    /// Free the block of `order` at `ptr`, merging it with its buddy for as long as the buddy is free
+    /// # Safety
+    /// `ptr` must be a block of `order` that is not in use, or in the free lists already
+    unsafe fn free_block(free_lists: &mut FreeLists<'_>, ptr: *mut u8, mut order: usize) {
+        let mut block = ptr as usize;
+        while order < MAX_ORDER {
+            let buddy = block ^ (PAGE_SIZE << order);
+            if !free_lists.is_free_block(buddy, order) {
+                break;
             }
+            unsafe { free_lists.remove(NonNull::new_unchecked(buddy as *mut Run), order) };
+            block = core::cmp::min(block, buddy);
+            order += 1;
         }
+        unsafe { free_lists.push(NonNull::new_unchecked(block as *mut Run), order) };
     }
+}
 
-    #[allow(clippy::cast_ptr_alignment)]
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let size = layout.size();
-        let align = layout.align();
+/// The index of the page holding `physical_address` in the reference counts of `memory_map`
+/// # Panics
+/// Panics if `physical_address` is not in a memory region
+fn convert_physical_to_index(memory_map: &PhysicalMemoryMap<'_>, physical_address: usize) -> usize {
+    memory_map
+        .page_index(physical_address)
+        .unwrap_or_else(|| panic!("KPA: 0x{physical_address:x} is not in physical memory"))
+}
 
-        // Pass off deallocations greater or equal to a page to the page allocator
-        // Size will delegate to the page allocator if it is bigger than
-        if size >= (PAGE_SIZE - (2 * core::mem::size_of::<TinyHeader>())) || align >= PAGE_SIZE {
-            unsafe { self.page_allocator.dealloc(ptr, layout) };
-        } else {
-            let ptr_int = ptr as usize;
-            if ptr_int % Self::MAX_ALIGNMENT != 0
-                || ptr_int < crate::end as usize
-                || ptr_int >= get_physical_memory_size()
-                || size > PAGE_SIZE
-                || align > PAGE_SIZE
-            {
-                panic!("KTA_dealloc: Out of bounds\0");
+unsafe impl GlobalAlloc for KernelAllocator<'_> {
+    /// Allocate from this hart's magazine for the smallest slab cache that fits `layout`, or for single pages,
+    /// refilling it from the global pool in a batch if it is empty. Larger blocks come from the page allocator
+    /// Returns null if memory has run out, leaving the OOM killer to callers that cannot do without the memory
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = if let Some(index) = self.slab_cache_for(layout) {
+            let slab_cache = &self.slab_caches[index];
+            let object = self.with_hart_cache(|hart_cache| {
+                hart_cache.slabs[index]
+                    .pop(|objects| slab_cache.alloc_batch(&self.page_allocator, objects))
+            });
+            if !object.is_null() {
+                unsafe { slab_cache.write_canary(object, layout.size()) };
             }
+            object
+        } else if KernelPageAllocator::order_for(layout) == Some(0) {
+            let page = self.with_hart_cache(|hart_cache| {
+                hart_cache
+                    .pages
+                    .pop(|pages| self.page_allocator.take_pages(pages))
+            });
+            if !page.is_null() {
+                self.page_allocator.take_reference(page);
+                unsafe { ptr::write_bytes(page, 5, PAGE_SIZE) };
+            }
+            page
+        } else {
+            unsafe { self.page_allocator.alloc(layout) }
+        };
+        #[cfg(feature = "kasan")]
+        if !ptr.is_null() {
+            kasan::allocate(ptr as usize, layout.size(), self.block_size(layout));
+        }
+        #[cfg(feature = "alloc-trace")]
+        if !ptr.is_null() {
+            alloc_trace::record(ptr, layout);
+        }
+        ptr
+    }
 
-            let header_list = self.tiny_page_list.lock();
-            let header = unsafe { ptr.cast::<TinyHeader>().offset(-1).as_mut().unwrap() };
-            header.next = Cell::new(header_list.get());
-            header_list.set(Some(header.into()));
+    /// Free into this hart's magazine for `layout`, flushing a batch to the global pool if it is full
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "kasan")]
+        self.kasan_free(ptr, layout);
+        #[cfg(feature = "alloc-trace")]
+        alloc_trace::forget(ptr);
+        if let Some(index) = self.slab_cache_for(layout) {
+            assert!(
+                self.page_allocator
+                    .memory_map()
+                    .is_physical_memory(ptr as usize),
+                "KSA_dealloc: Out of bounds"
+            );
+            let slab_cache = &self.slab_caches[index];
+            assert!(
+                unsafe { slab_cache.canary_intact(ptr, layout.size()) },
+                "KSA_dealloc: Overrun"
+            );
+            self.with_hart_cache(|hart_cache| {
+                // Objects in the slab itself are checked when the magazine is flushed
+                assert!(
+                    !hart_cache.slabs[index].contains(ptr),
+                    "KSA_dealloc: Double free"
+                );
+                hart_cache.slabs[index].push(ptr, |objects| unsafe {
+                    slab_cache.dealloc_batch(&self.page_allocator, objects);
+                });
+            });
+        } else if self.page_allocator.check_dealloc(ptr, layout) == 0 {
+            // Only actually deallocate if we have 0 references
+            if self.page_allocator.drop_reference(ptr) {
+                unsafe { ptr::write_bytes(ptr, 1, PAGE_SIZE) };
+                self.with_hart_cache(|hart_cache| {
+                    hart_cache.pages.push(ptr, |pages| unsafe {
+                        self.page_allocator.return_pages(pages);
+                    });
+                });
+            }
+        } else {
+            unsafe { self.page_allocator.dealloc(ptr, layout) };
         }
     }
 
-    #[allow(clippy::cast_ptr_alignment)]
+    /// Keep the allocation where it is if the new size still needs the same slab cache or block order,
+    /// moving its canary to the new end
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        let old_size = layout.size();
-        let align = layout.align();
-        if align >= PAGE_SIZE {
-            unsafe { self.page_allocator.realloc(ptr, layout, new_size) }
-        } else if old_size >= PAGE_SIZE - (2 * core::mem::size_of::<TinyHeader>()) {
-            if new_size <= old_size {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        match (self.slab_cache_for(layout), self.slab_cache_for(new_layout)) {
+            (Some(old_cache), Some(new_cache)) if old_cache == new_cache => {
+                let slab_cache = &self.slab_caches[old_cache];
+                assert!(
+                    unsafe { slab_cache.canary_intact(ptr, layout.size()) },
+                    "KSA_realloc: Overrun"
+                );
+                unsafe { slab_cache.write_canary(ptr, new_size) };
+                #[cfg(feature = "kasan")]
+                kasan::resize(ptr as usize, new_size, slab_cache.object_size());
+                #[cfg(feature = "alloc-trace")]
+                alloc_trace::resize(ptr, new_size);
                 ptr
-            } else {
-                unsafe { self.page_allocator.realloc(ptr, layout, new_size) }
             }
-        } else {
-            match unsafe { ptr.cast::<TinyHeader>().sub(1).as_ref() } {
-                Some(header) => {
-                    if header.size >= new_size {
-                        ptr
-                    } else {
-                        self.default_realloc(ptr, layout, new_size)
-                    }
-                }
-                None => self.default_realloc(ptr, layout, new_size),
+            (None, None)
+                if KernelPageAllocator::order_for(layout)
+                    == KernelPageAllocator::order_for(new_layout) =>
+            {
+                #[cfg(feature = "kasan")]
+                kasan::resize(ptr as usize, new_size, self.block_size(layout));
+                #[cfg(feature = "alloc-trace")]
+                alloc_trace::resize(ptr, new_size);
+                ptr
             }
+            _ => self.default_realloc(ptr, layout, new_size),
         }
     }
 }
 
-impl KernelAllocator<'_> {
-    const MAX_ALIGNMENT: usize = 16;
-
+impl KernelAllocator<'static> {
+    /// Manage the memory regions in the FDT, with the allocator's metadata just past the kernel
     pub fn init(&self) {
-        let page_count =
-            (PGROUNDDOWN!(get_physical_memory_size()) - PGROUNDUP!(crate::end)) / PAGE_SIZE;
-        self.page_allocator.init(page_count);
+        self.init_with_memory(PhysicalMemoryMap::from_fdt(), crate::end as usize);
     }
+}
 
+impl<'a> KernelAllocator<'a> {
+    // Only ever evaluated at compile time for `ALLOCATOR`, or boxed by host tests
+    #[allow(clippy::large_stack_arrays)]
+    pub(crate) const fn new() -> Self {
+        Self {
+            page_allocator: KernelPageAllocator::new(),
+            slab_caches: [
+                SlabCache::new(16, 0),
+                SlabCache::new(32, 0),
+                SlabCache::new(64, 0),
+                SlabCache::new(128, 0),
+                SlabCache::new(256, 0),
+                SlabCache::new(512, 0),
+                SlabCache::new(1024, 1),
+                SlabCache::new(2048, 2),
+            ],
+            hart_caches: [const { Mutex::new(HartCache::new()) }; MAX_HART_COUNT],
+        }
+    }
+
+    /// Manage the memory in `memory_map`, with the allocator's metadata at `metadata_address`
+    pub(crate) fn init_with_memory(
+        &self,
+        memory_map: PhysicalMemoryMap<'a>,
+        metadata_address: usize,
+    ) {
+        self.page_allocator.init(memory_map, metadata_address);
+    }
+
+    /// The index of the page holding `physical_address` in the page allocator, if it manages that page
     #[allow(dead_code)]
-    pub(crate) fn memfree_count(&self) -> usize {
-        let tiny_space = {
-            let tiny_allocations = self.tiny_page_list.lock();
-            let mut list_ptr = tiny_allocations.get();
-            let mut tiny_space = 0usize;
-            while list_ptr.is_some() {
-                if let Some(ptr) = list_ptr {
-                    let data_ref = unsafe { ptr.as_ptr().as_ref() }.unwrap();
-                    tiny_space += data_ref.size;
-                    list_ptr = data_ref.next.get();
-                }
-            }
-            tiny_space
+    pub(crate) fn physical_to_index(&self, physical_address: usize) -> Option<usize> {
+        self.page_allocator.physical_to_index(physical_address)
+    }
+
+    pub(crate) fn memfree_count(&self) -> MemoryStatistics {
+        let magazine_bytes = self
+            .hart_caches
+            .iter()
+            .map(|hart_cache| {
+                let hart_cache = hart_cache.lock();
+                hart_cache.pages.len() * PAGE_SIZE
+                    + hart_cache
+                        .slabs
+                        .iter()
+                        .zip(&self.slab_caches)
+                        .map(|(magazine, slab_cache)| magazine.len() * slab_cache.object_size())
+                        .sum::<usize>()
+            })
+            .sum();
+        MemoryStatistics {
+            free_page_bytes: self.page_allocator.pfree_count(),
+            magazine_bytes,
+            slab_caches: core::array::from_fn(|index| self.slab_caches[index].statistics()),
+        }
+    }
+
+    /// The index of the smallest slab cache that fits `layout`, if any are big enough
+    fn slab_cache_for(&self, layout: Layout) -> Option<usize> {
+        self.slab_caches
+            .iter()
+            .position(|slab_cache| slab_cache.fits(layout))
+    }
+
+    /// The size of the slab object or block of pages allocated for `layout`
+    #[cfg(feature = "kasan")]
+    fn block_size(&self, layout: Layout) -> usize {
+        match self.slab_cache_for(layout) {
+            Some(index) => self.slab_caches[index].object_size(),
+            None => PAGE_SIZE << KernelPageAllocator::order_for(layout).unwrap_or(0),
+        }
+    }
+
+    /// Check that `ptr` is a live allocation for `layout` and poison it, unless it is a page that is still
+    /// referenced elsewhere
+    #[cfg(feature = "kasan")]
+    fn kasan_free(&self, ptr: *mut u8, layout: Layout) {
+        let poison = if self.slab_cache_for(layout).is_some() {
+            Poison::SlabFree
+        } else if self.page_allocator.exactly_one_reference(ptr as usize) {
+            Poison::PageFree
+        } else {
+            return;
         };
-        tiny_space + self.page_allocator.pfree_count()
+        kasan::free(ptr as usize, layout.size(), self.block_size(layout), poison);
+    }
+
+    /// Run `f` on this hart's magazines, with interrupts off so that nothing else on this hart can use them
+    fn with_hart_cache<T>(&self, f: impl FnOnce(&mut HartCache) -> T) -> T {
+        let _interrupts_off = InterruptsOff::new();
+        f(&mut self.hart_caches[cpuid()].lock())
     }
 
     #[allow(dead_code)]
@@ -404,32 +689,6 @@ impl KernelAllocator<'_> {
             && self.page_allocator.exactly_one_reference(physical_address)
     }
 
-    fn write_blocks(
-        prev: &mut Option<NonNull<TinyHeader>>,
-        tiny_list: &MutexGuard<'_, Cell<Option<NonNull<TinyHeader>>>>,
-        header: &mut TinyHeader,
-        size: usize,
-    ) -> *mut u8 {
-        if header.size > size {
-            header.size -= size + core::mem::size_of::<TinyHeader>();
-            let new_header = unsafe { ptr::addr_of_mut!(*header).add(1).byte_add(header.size) };
-            unsafe {
-                *new_header = TinyHeader {
-                    next: Cell::new(None),
-                    size,
-                };
-            }
-            unsafe { new_header.add(1).cast() }
-        } else {
-            if let Some(mut prev) = prev {
-                unsafe { prev.as_mut() }.next = Cell::new(header.next.get());
-            } else {
-                tiny_list.set(header.next.get());
-            }
-            unsafe { ptr::addr_of_mut!(*header).add(1).cast::<u8>() }
-        }
-    }
-
     fn default_realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
         // SAFETY: the caller must ensure that `new_layout` is greater than zero.
@@ -445,3 +704,404 @@ impl KernelAllocator<'_> {
         new_ptr
     }
 }
+
+impl MemoryStatistics {
+    /// The bytes free in the page allocator and in the slab caches together
+    pub(crate) fn free_bytes(&self) -> usize {
+        self.free_page_bytes
+            + self.magazine_bytes
+            + self
+                .slab_caches
+                .iter()
+                .map(|slab_cache| slab_cache.objects_free * slab_cache.object_size)
+                .sum::<usize>()
+    }
+}
+
+/// Shared with the slab tests, which drive a whole allocator
+/// A small xorshift generator for the allocator tests, which always starts from the same seed so that failures are
+/// reproducible
+#[cfg(test)]
+pub(crate) struct Random(usize);
+
+#[cfg(test)]
+impl Random {
+    pub(crate) const fn new() -> Self {
+        Self(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub(crate) fn next(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number from 0 up to, but not including, `bound`
+    pub(crate) fn below(&mut self, bound: usize) -> usize {
+        self.next() % bound
+    }
+}
+
+#[cfg(all(test, host))]
+pub(crate) mod tests {
+    use super::*;
+    use std::alloc::System;
+    use std::boxed::Box;
+    use std::collections::BTreeSet;
+    use std::vec::Vec;
+
+    /// Two blocks of the largest order
+    const ARENA_SIZE: usize = 2 * (PAGE_SIZE << MAX_ORDER);
+    const ARENA_PAGES: usize = ARENA_SIZE / PAGE_SIZE;
+
+    /// Memory on the host heap for the allocator to manage, aligned so that it holds whole blocks of every order,
+    /// with its metadata in a separate buffer
+    pub(crate) struct Arena {
+        memory: *mut u8,
+        metadata: *mut u8,
+    }
+
+    impl Arena {
+        pub(crate) fn new() -> Self {
+            Self {
+                memory: unsafe { System.alloc(Self::memory_layout()) },
+                metadata: unsafe { System.alloc(Self::metadata_layout()) },
+            }
+        }
+
+        fn memory_layout() -> Layout {
+            Layout::from_size_align(ARENA_SIZE, PAGE_SIZE << MAX_ORDER).unwrap()
+        }
+
+        fn metadata_layout() -> Layout {
+            Layout::from_size_align((2 + SHADOW_BYTES_PER_PAGE) * ARENA_PAGES, PAGE_SIZE).unwrap()
+        }
+
+        pub(crate) fn region(&self) -> PhysicalRange {
+            PhysicalRange {
+                start: self.memory as usize,
+                end: self.memory as usize + ARENA_SIZE,
+            }
+        }
+
+        fn page(&self, index: usize) -> PhysicalRange {
+            let start = self.memory as usize + index * PAGE_SIZE;
+            PhysicalRange {
+                start,
+                end: start + PAGE_SIZE,
+            }
+        }
+    }
+
+    impl Drop for Arena {
+        fn drop(&mut self) {
+            unsafe {
+                System.dealloc(self.memory, Self::memory_layout());
+                System.dealloc(self.metadata, Self::metadata_layout());
+            }
+        }
+    }
+
+    fn page_allocator<'a>(
+        arena: &Arena,
+        regions: &'a [PhysicalRange],
+        reserved: &'a [PhysicalRange],
+    ) -> KernelPageAllocator<'a> {
+        let page_allocator = KernelPageAllocator::new();
+        page_allocator.init(
+            PhysicalMemoryMap { regions, reserved },
+            arena.metadata as usize,
+        );
+        page_allocator
+    }
+
+    pub(crate) fn allocator<'a>(
+        arena: &Arena,
+        regions: &'a [PhysicalRange],
+    ) -> Box<KernelAllocator<'a>> {
+        let allocator = Box::new(KernelAllocator::new());
+        allocator.init_with_memory(
+            PhysicalMemoryMap {
+                regions,
+                reserved: &[],
+            },
+            arena.metadata as usize,
+        );
+        allocator
+    }
+
+    fn pages(count: usize) -> Layout {
+        Layout::from_size_align(count * PAGE_SIZE, PAGE_SIZE).unwrap()
+    }
+
+    #[test]
+    fn blocks_merge_back_with_their_buddies() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let page_allocator = page_allocator(&arena, &regions, &[]);
+        assert_eq!(page_allocator.pfree_count(), ARENA_SIZE);
+
+        let blocks: Vec<_> = [1, 3, 8, 1, 512]
+            .into_iter()
+            .map(|count| {
+                let block = unsafe { page_allocator.alloc(pages(count)) };
+                assert!(!block.is_null());
+                let size = count.next_power_of_two() * PAGE_SIZE;
+                assert_eq!(block as usize % size, 0, "block is not aligned to its size");
+                (block, count)
+            })
+            .collect();
+        let used: usize = blocks
+            .iter()
+            .map(|&(_, count)| count.next_power_of_two() * PAGE_SIZE)
+            .sum();
+        assert_eq!(page_allocator.pfree_count(), ARENA_SIZE - used);
+
+        for (block, count) in blocks {
+            unsafe { page_allocator.dealloc(block, pages(count)) };
+        }
+        assert_eq!(page_allocator.pfree_count(), ARENA_SIZE);
+        // Only whole blocks of the largest order are left
+        let largest = pages(1 << MAX_ORDER);
+        let first = unsafe { page_allocator.alloc(largest) };
+        let second = unsafe { page_allocator.alloc(largest) };
+        assert!(!first.is_null() && !second.is_null());
+        assert!(unsafe { page_allocator.alloc(pages(1)) }.is_null());
+    }
+
+    #[test]
+    fn blocks_larger_than_the_largest_order_fail() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let page_allocator = page_allocator(&arena, &regions, &[]);
+        assert!(unsafe { page_allocator.alloc(pages((1 << MAX_ORDER) + 1)) }.is_null());
+        assert_eq!(page_allocator.pfree_count(), ARENA_SIZE);
+    }
+
+    #[test]
+    fn reserved_pages_are_never_handed_out() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let reserved = [arena.page(5)];
+        let page_allocator = page_allocator(&arena, &regions, &reserved);
+        assert_eq!(page_allocator.pfree_count(), ARENA_SIZE - PAGE_SIZE);
+
+        let mut handed_out = BTreeSet::new();
+        loop {
+            let page = unsafe { page_allocator.alloc(pages(1)) };
+            if page.is_null() {
+                break;
+            }
+            assert!(!reserved[0].contains(page as usize));
+            assert!(handed_out.insert(page as usize), "page handed out twice");
+        }
+        assert_eq!(handed_out.len(), ARENA_PAGES - 1);
+    }
+
+    #[test]
+    fn shared_pages_are_freed_by_the_last_reference() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let page_allocator = page_allocator(&arena, &regions, &[]);
+        let page = unsafe { page_allocator.alloc(pages(1)) };
+        assert!(page_allocator.exactly_one_reference(page as usize));
+
+        page_allocator.in_place_copy(page as usize);
+        assert!(!page_allocator.exactly_one_reference(page as usize));
+        unsafe { page_allocator.dealloc(page, pages(1)) };
+        assert!(page_allocator.exactly_one_reference(page as usize));
+        assert_eq!(page_allocator.pfree_count(), ARENA_SIZE - PAGE_SIZE);
+
+        unsafe { page_allocator.dealloc(page, pages(1)) };
+        assert_eq!(page_allocator.pfree_count(), ARENA_SIZE);
+    }
+
+    #[test]
+    fn pages_are_taken_and_returned_in_batches() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let page_allocator = page_allocator(&arena, &regions, &[]);
+        let mut taken = [ptr::null_mut(); 16];
+        assert_eq!(page_allocator.take_pages(&mut taken), taken.len());
+        let distinct: BTreeSet<_> = taken.iter().map(|&page| page as usize).collect();
+        assert_eq!(distinct.len(), taken.len());
+        assert_eq!(page_allocator.pfree_count(), ARENA_SIZE - 16 * PAGE_SIZE);
+
+        unsafe { page_allocator.return_pages(&taken) };
+        assert_eq!(page_allocator.pfree_count(), ARENA_SIZE);
+    }
+
+    #[test]
+    #[should_panic(expected = "KPA_dealloc: Out of bounds")]
+    fn freeing_a_misaligned_block_panics() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let page_allocator = page_allocator(&arena, &regions, &[]);
+        page_allocator.check_dealloc(arena.page(1).start as *mut u8, pages(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "KPA_dealloc: Out of bounds")]
+    fn freeing_a_reserved_page_panics() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let reserved = [arena.page(3)];
+        let page_allocator = page_allocator(&arena, &regions, &reserved);
+        page_allocator.check_dealloc(reserved[0].start as *mut u8, pages(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "KPA_dealloc: Out of bounds")]
+    fn freeing_outside_memory_panics() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let page_allocator = page_allocator(&arena, &regions, &[]);
+        page_allocator.check_dealloc(arena.region().end as *mut u8, pages(1));
+    }
+
+    #[test]
+    fn single_pages_go_through_the_magazine() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let allocator = allocator(&arena, &regions);
+        let page = unsafe { allocator.alloc(pages(1)) };
+        assert!(!page.is_null());
+        let statistics = allocator.memfree_count();
+        // The magazine is refilled with a batch, of which this page is one
+        assert_eq!(statistics.magazine_bytes, 15 * PAGE_SIZE);
+        assert_eq!(statistics.free_bytes(), ARENA_SIZE - PAGE_SIZE);
+
+        unsafe { allocator.dealloc(page, pages(1)) };
+        assert_eq!(allocator.memfree_count().free_bytes(), ARENA_SIZE);
+    }
+
+    #[test]
+    fn larger_blocks_bypass_the_magazine() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let allocator = allocator(&arena, &regions);
+        let block = unsafe { allocator.alloc(pages(3)) };
+        let statistics = allocator.memfree_count();
+        assert_eq!(statistics.magazine_bytes, 0);
+        assert_eq!(statistics.free_page_bytes, ARENA_SIZE - 4 * PAGE_SIZE);
+
+        unsafe { allocator.dealloc(block, pages(3)) };
+        assert_eq!(allocator.memfree_count().free_page_bytes, ARENA_SIZE);
+    }
+
+    #[test]
+    fn small_objects_come_from_slabs() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let allocator = allocator(&arena, &regions);
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let objects: Vec<_> = (0..100u8)
+            .map(|tag| {
+                let object = unsafe { allocator.alloc(layout) };
+                assert!(regions[0].contains(object as usize));
+                unsafe { object.write_bytes(tag, layout.size()) };
+                object
+            })
+            .collect();
+        for (tag, &object) in (0..100u8).zip(&objects) {
+            let contents = unsafe { core::slice::from_raw_parts(object, layout.size()) };
+            assert!(contents.iter().all(|&byte| byte == tag));
+        }
+
+        // Growing within the same slab cache keeps the object where it is
+        let grown = unsafe { allocator.realloc(objects[0], layout, 32) };
+        assert_eq!(grown, objects[0]);
+        // Growing past it moves the object, keeping its contents
+        let moved =
+            unsafe { allocator.realloc(grown, Layout::from_size_align(32, 8).unwrap(), 100) };
+        assert_ne!(moved, grown);
+        assert!(unsafe { core::slice::from_raw_parts(moved, layout.size()) }
+            .iter()
+            .all(|&byte| byte == 0));
+
+        unsafe { allocator.dealloc(moved, Layout::from_size_align(100, 8).unwrap()) };
+        for &object in &objects[1..] {
+            unsafe { allocator.dealloc(object, layout) };
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "KSA_dealloc: Double free")]
+    fn freeing_an_object_twice_panics() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let allocator = allocator(&arena, &regions);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let object = unsafe { allocator.alloc(layout) };
+        unsafe {
+            allocator.dealloc(object, layout);
+            allocator.dealloc(object, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "KSA_dealloc: Overrun")]
+    fn writing_past_an_object_panics() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let allocator = allocator(&arena, &regions);
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let object = unsafe { allocator.alloc(layout) };
+        unsafe {
+            object.add(layout.size()).write(0);
+            allocator.dealloc(object, layout);
+        }
+    }
+}
+
+#[cfg(all(test, not(host)))]
+mod kernel_tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn allocator_stress() {
+        let mut random = Random::new();
+        let mut live: Vec<Vec<u8>> = Vec::new();
+        for _ in 0..4096 {
+            if live.len() >= 64 || (random.below(3) == 0 && !live.is_empty()) {
+                let buffer = live.swap_remove(random.below(live.len()));
+                let tag = buffer.len().to_le_bytes()[0];
+                assert!(
+                    buffer.iter().all(|&byte| byte == tag),
+                    "allocation corrupted"
+                );
+            } else {
+                let size = 1 + random.below(3 * PAGE_SIZE);
+                live.push(alloc::vec![size.to_le_bytes()[0]; size]);
+            }
+        }
+    }
+
+    #[test_case]
+    fn largest_blocks_are_aligned_and_come_back() {
+        let layout = Layout::from_size_align(PAGE_SIZE << MAX_ORDER, PAGE_SIZE).unwrap();
+        let free_before = ALLOCATOR.memfree_count().free_page_bytes;
+        let block = unsafe { ALLOCATOR.alloc(layout) };
+        assert!(!block.is_null());
+        assert_eq!(block as usize % layout.size(), 0);
+        assert_eq!(
+            ALLOCATOR.memfree_count().free_page_bytes,
+            free_before - layout.size()
+        );
+        unsafe { ALLOCATOR.dealloc(block, layout) };
+        assert_eq!(ALLOCATOR.memfree_count().free_page_bytes, free_before);
+    }
+
+    #[test_case]
+    fn shared_pages_outlive_their_first_free() {
+        let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+        let page = unsafe { ALLOCATOR.alloc(layout) };
+        ALLOCATOR.in_place_copy(page as usize);
+        assert!(!ALLOCATOR.exactly_one_reference(page as usize));
+        unsafe { ALLOCATOR.dealloc(page, layout) };
+        assert!(ALLOCATOR.exactly_one_reference(page as usize));
+        unsafe { ALLOCATOR.dealloc(page, layout) };
+    }
+}
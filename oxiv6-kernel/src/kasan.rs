@@ -0,0 +1,265 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! Shadow memory for the kernel heap, in the style of the kernel address sanitizer
+//!
+//! Every 8 byte granule of managed physical memory has a shadow byte: 0 if the whole granule may be used,
+//! 1 to 7 if only that many bytes at its start may be, or a [`Poison`] value if none of it may be. The
+//! allocator unpoisons memory as it hands it out and poisons it as it is freed, and accesses are checked
+//! against the shadow at the allocator's entry points and wherever the kernel calls [`check`]
+
+use crate::backtrace::return_addresses;
+use crate::kalloc::ALLOCATOR;
+use crate::magazine::InterruptsOff;
+use crate::println::println;
+use crate::symbols::Locations;
+use crate::vm::PAGE_SIZE;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::{mutex::Mutex, once::Once};
+
+/// The bytes of memory each shadow byte covers
+const GRANULE_SIZE: usize = 8;
+/// The shadow bytes needed for each page of managed memory
+pub(crate) const SHADOW_BYTES_PER_PAGE: usize = PAGE_SIZE / GRANULE_SIZE;
+/// How many allocations have their allocation and free sites remembered for reports
+const RECORD_COUNT: usize = 512;
+/// How many return addresses are kept for each allocation and free site
+const SITE_DEPTH: usize = 4;
+/// The frames to leave out of allocation and free sites, for this module and the allocator itself
+const ALLOCATOR_FRAMES: usize = 2;
+
+static SHADOW: Once<&'static [AtomicU8]> = Once::new();
+
+/// Recent allocations, indexed by a hash of their address. A newer allocation replaces an older one
+/// with the same hash, so a report may not have the sites of an old allocation
+static RECORDS: Mutex<[AllocationRecord; RECORD_COUNT]> = Mutex::new(
+    [AllocationRecord {
+        address: 0,
+        size: 0,
+        block_size: 0,
+        allocated_at: [0; SITE_DEPTH],
+        freed_at: [0; SITE_DEPTH],
+    }; RECORD_COUNT],
+);
+
+/// Why a granule may not be used
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Poison {
+    /// The page is free in the page allocator, or a magazine
+    PageFree = 0xff,
+    /// Past the end of an allocation, in the rest of its slab object or block
+    Redzone = 0xfc,
+    /// The slab object is free
+    SlabFree = 0xfb,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy)]
+struct AllocationRecord {
+    address: usize,
+    size: usize,
+    block_size: usize,
+    allocated_at: [usize; SITE_DEPTH],
+    /// All zeroes while the allocation is live
+    freed_at: [usize; SITE_DEPTH],
+}
+
+/// Use the `page_count * SHADOW_BYTES_PER_PAGE` bytes at `shadow_address` as the shadow of managed memory,
+/// with all of it usable until the page allocator poisons its free pages
+pub(crate) fn init(shadow_address: usize, page_count: usize) {
+    let shadow_size = page_count * SHADOW_BYTES_PER_PAGE;
+    unsafe { core::ptr::write_bytes(shadow_address as *mut u8, 0, shadow_size) };
+    SHADOW.call_once(|| unsafe {
+        core::slice::from_raw_parts(shadow_address as *const AtomicU8, shadow_size)
+    });
+}
+
+/// Mark the first `size` bytes at `address` as usable. `address` must be at the start of a granule
+pub(crate) fn unpoison(address: usize, size: usize) {
+    for granule in (address..address + size).step_by(GRANULE_SIZE) {
+        let usable = core::cmp::min(address + size - granule, GRANULE_SIZE);
+        #[allow(clippy::cast_possible_truncation)]
+        set_shadow(granule, (usable % GRANULE_SIZE) as u8);
+    }
+}
+
+/// Mark the granules from `address` up to `address + size` as unusable for `poison`. `address` must be
+/// at the start of a granule
+pub(crate) fn poison(address: usize, size: usize, poison: Poison) {
+    for granule in (address..address + size).step_by(GRANULE_SIZE) {
+        set_shadow(granule, poison as u8);
+    }
+}
+
+/// Give out the `size` bytes at the start of the block of `block_size` bytes at `address`, poisoning the rest
+/// of the block as a redzone, and remember the allocator's callers as where it was allocated
+#[inline(never)]
+pub(crate) fn allocate(address: usize, size: usize, block_size: usize) {
+    unpoison_with_redzone(address, size, block_size);
+
+    let mut allocated_at = [0; SITE_DEPTH];
+    return_addresses(ALLOCATOR_FRAMES, &mut allocated_at);
+    let _interrupts_off = InterruptsOff::new();
+    RECORDS.lock()[record_index(address)] = AllocationRecord {
+        address,
+        size,
+        block_size,
+        allocated_at,
+        freed_at: [0; SITE_DEPTH],
+    };
+}
+
+/// Check that the `size` bytes at `address` are a live allocation, before the block of `block_size` bytes
+/// holding them is poisoned for `poison_as` and the allocator's callers remembered as where it was freed
+/// # Panics
+/// Panics with a report if the memory was already freed, or is not the start of an allocation
+#[inline(never)]
+pub(crate) fn free(address: usize, size: usize, block_size: usize, poison_as: Poison) {
+    if let Some(bad) = first_bad_granule(address, size) {
+        let kind = match shadow(bad) {
+            Some(shadow) if address == bad && is_freed(shadow) => "double-free",
+            _ => "invalid-free",
+        };
+        report(kind, bad, "free", address, size);
+    }
+    poison(address, block_size, poison_as);
+
+    let mut freed_at = [0; SITE_DEPTH];
+    return_addresses(ALLOCATOR_FRAMES, &mut freed_at);
+    let _interrupts_off = InterruptsOff::new();
+    let mut records = RECORDS.lock();
+    let record = &mut records[record_index(address)];
+    if record.address == address {
+        record.freed_at = freed_at;
+    }
+}
+
+/// Give out `size` bytes of the allocation at `address` instead, after an in-place realloc
+pub(crate) fn resize(address: usize, size: usize, block_size: usize) {
+    unpoison_with_redzone(address, size, block_size);
+
+    let _interrupts_off = InterruptsOff::new();
+    let mut records = RECORDS.lock();
+    let record = &mut records[record_index(address)];
+    if record.address == address {
+        record.size = size;
+    }
+}
+
+/// Check that the `size` bytes at `address` may be accessed
+/// # Panics
+/// Panics with a report if any of them are poisoned
+pub(crate) fn check(address: usize, size: usize, access: Access) {
+    if let Some(bad) = first_bad_granule(address, size) {
+        let kind = match shadow(bad) {
+            Some(shadow) if is_freed(shadow) => "use-after-free",
+            _ => "out-of-bounds",
+        };
+        let access = match access {
+            Access::Read => "read",
+            Access::Write => "write",
+        };
+        report(kind, bad, access, address, size);
+    }
+}
+
+/// The first granule holding a byte of `[address, address + size)` that may not be used, if any
+fn first_bad_granule(address: usize, size: usize) -> Option<usize> {
+    let end = address + size;
+    let mut granule = address - address % GRANULE_SIZE;
+    while granule < end {
+        let usable = match shadow(granule) {
+            Some(0) | None => GRANULE_SIZE,
+            Some(shadow) if usize::from(shadow) < GRANULE_SIZE => usize::from(shadow),
+            Some(_) => 0,
+        };
+        if core::cmp::min(end, granule + GRANULE_SIZE) > granule + usable {
+            return Some(granule);
+        }
+        granule += GRANULE_SIZE;
+    }
+    None
+}
+
+/// Unpoison the first `size` bytes of the block of `block_size` bytes at `address`, and poison the rest
+fn unpoison_with_redzone(address: usize, size: usize, block_size: usize) {
+    let redzone = size.next_multiple_of(GRANULE_SIZE);
+    unpoison(address, size);
+    poison(
+        address + redzone,
+        block_size.saturating_sub(redzone),
+        Poison::Redzone,
+    );
+}
+
+fn is_freed(shadow: u8) -> bool {
+    shadow == Poison::PageFree as u8 || shadow == Poison::SlabFree as u8
+}
+
+/// Print what went wrong at `bad`, and where the allocation it is in was allocated and freed if that is
+/// remembered, then panic
+fn report(kind: &str, bad: usize, access: &str, address: usize, size: usize) -> ! {
+    println!(
+        "KASAN: {kind} at 0x{bad:x}, in {access} of {size} bytes at 0x{address:x}, shadow 0x{:02x}",
+        shadow(bad).unwrap_or(0)
+    );
+    let records = RECORDS.lock();
+    let record = records
+        .iter()
+        .filter(|record| (record.address..record.address + record.block_size).contains(&bad))
+        .max_by_key(|record| record.address);
+    if let Some(record) = record {
+        println!(
+            "  in the {} byte allocation at 0x{:x}",
+            record.size, record.address
+        );
+        println!("  allocated at {}", Locations(&record.allocated_at));
+        if record.freed_at[0] != 0 {
+            println!("  freed at {}", Locations(&record.freed_at));
+        }
+    } else {
+        println!("  no record of the allocation");
+    }
+    drop(records);
+    panic!("KASAN: {kind} at 0x{bad:x}");
+}
+
+fn record_index(address: usize) -> usize {
+    (address / GRANULE_SIZE) % RECORD_COUNT
+}
+
+fn shadow_index(address: usize) -> Option<usize> {
+    let page = ALLOCATOR.physical_to_index(address)?;
+    Some(page * SHADOW_BYTES_PER_PAGE + (address % PAGE_SIZE) / GRANULE_SIZE)
+}
+
+/// The shadow byte of the granule holding `address`, if it is in managed memory and the shadow is set up
+fn shadow(address: usize) -> Option<u8> {
+    let shadow = SHADOW.get()?;
+    Some(shadow[shadow_index(address)?].load(Ordering::Relaxed))
+}
+
+fn set_shadow(address: usize, value: u8) {
+    if let Some((shadow, index)) = SHADOW.get().zip(shadow_index(address)) {
+        shadow[index].store(value, Ordering::Relaxed);
+    }
+}
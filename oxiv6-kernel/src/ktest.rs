@@ -0,0 +1,43 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! The test runner for `#[test_case]`s, which run inside the kernel once it has booted on the boot hart.
+//! A failing test panics, and the panic handler resets the system with `SystemFailure`, so the exit status of
+//! QEMU says whether every test passed
+
+use crate::println::{print, println};
+
+/// A test the runner can run, printing its name and result
+pub(crate) trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        print!("test {} ... ", core::any::type_name::<T>());
+        self();
+        println!("ok");
+    }
+}
+
+/// Run every test in turn, returning if they all pass
+pub(crate) fn run_tests(tests: &[&dyn Testable]) {
+    println!("running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    println!("test result: ok. {} passed", tests.len());
+}
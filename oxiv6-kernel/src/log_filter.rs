@@ -0,0 +1,248 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! Which log records are printed: a default level, and levels for the targets named in filters such as
+//! `info,kalloc=debug,vm=trace`. A target is a module path, with or without the leading `oxiv6_kernel::`, and
+//! covers the modules inside it. `oxiv6_kernel` on its own covers the whole kernel, but not the crates it uses.
+//! The most specific filter for a record's target wins.
+
+use core::fmt;
+use log::LevelFilter;
+
+/// The most targets with their own level
+const MAX_TARGET_FILTERS: usize = 16;
+/// The longest target name, such as `dev::virtio`
+const MAX_TARGET_LENGTH: usize = 48;
+/// Log targets in this crate start with its name, which filters may leave out
+const CRATE_PREFIX: &str = "oxiv6_kernel::";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LogFilterError {
+    /// A level was neither a level name nor a number from 0 (off) to 5 (trace)
+    BadLevel,
+    /// A target was empty, or longer than [`MAX_TARGET_LENGTH`]
+    BadTarget,
+    /// More than [`MAX_TARGET_FILTERS`] targets would have their own level
+    TooManyTargets,
+}
+
+/// The level for the modules under one target
+#[derive(Debug, Clone, Copy)]
+struct TargetFilter {
+    name: [u8; MAX_TARGET_LENGTH],
+    length: usize,
+    level: LevelFilter,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LogFilters {
+    default: LevelFilter,
+    targets: [Option<TargetFilter>; MAX_TARGET_FILTERS],
+}
+
+impl TargetFilter {
+    fn name(&self) -> &str {
+        // Only ever copied from a `&str`
+        core::str::from_utf8(&self.name[..self.length]).unwrap()
+    }
+
+    /// Whether this filter covers `target`, which has no crate prefix
+    fn covers(&self, target: &str) -> bool {
+        target
+            .strip_prefix(self.name())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    }
+}
+
+impl LogFilters {
+    /// Filters printing everything up to `default`, with no targets of their own
+    pub(crate) const fn new(default: LevelFilter) -> Self {
+        Self {
+            default,
+            targets: [None; MAX_TARGET_FILTERS],
+        }
+    }
+
+    /// Change the filters with the comma separated `filters`, each either a level for every target without its
+    /// own, or `target=level`. Nothing changes unless every filter is well formed
+    pub(crate) fn apply(&mut self, filters: &str) -> Result<(), LogFilterError> {
+        let mut changed = *self;
+        for filter in filters.split(',').map(str::trim) {
+            if filter.is_empty() {
+                continue;
+            }
+            match filter.split_once('=') {
+                None => changed.default = parse_level(filter)?,
+                Some((target, level)) => changed.set(target.trim(), parse_level(level.trim())?)?,
+            }
+        }
+        *self = changed;
+        Ok(())
+    }
+
+    /// Give `target` and the modules under it their own level
+    fn set(&mut self, target: &str, level: LevelFilter) -> Result<(), LogFilterError> {
+        let target = target.strip_prefix(CRATE_PREFIX).unwrap_or(target);
+        if target.is_empty() || target.len() > MAX_TARGET_LENGTH {
+            return Err(LogFilterError::BadTarget);
+        }
+        if let Some(filter) = self
+            .targets
+            .iter_mut()
+            .flatten()
+            .find(|filter| filter.name() == target)
+        {
+            filter.level = level;
+            return Ok(());
+        }
+        let slot = self
+            .targets
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(LogFilterError::TooManyTargets)?;
+        let mut name = [0; MAX_TARGET_LENGTH];
+        name[..target.len()].copy_from_slice(target.as_bytes());
+        *slot = Some(TargetFilter {
+            name,
+            length: target.len(),
+            level,
+        });
+        Ok(())
+    }
+
+    /// The most verbose level printed for `target`. A kernel module's own filters are more specific than one for
+    /// the whole kernel
+    pub(crate) fn level(&self, target: &str) -> LevelFilter {
+        let most_specific = |target: &str| {
+            self.targets
+                .iter()
+                .flatten()
+                .filter(|filter| filter.covers(target))
+                .max_by_key(|filter| filter.length)
+        };
+        target
+            .strip_prefix(CRATE_PREFIX)
+            .and_then(most_specific)
+            .or_else(|| most_specific(target))
+            .map_or(self.default, |filter| filter.level)
+    }
+
+    /// The most verbose level printed for any target, past which `log` need not ask
+    pub(crate) fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .flatten()
+            .map(|filter| filter.level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl fmt::Display for LogFilters {
+    /// Show the filters in the form [`LogFilters::apply`] takes
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default)?;
+        for filter in self.targets.iter().flatten() {
+            write!(f, ",{}={}", filter.name(), filter.level)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a level name, or a number counting up from `off`
+fn parse_level(value: &str) -> Result<LevelFilter, LogFilterError> {
+    value
+        .parse()
+        .ok()
+        .or_else(|| {
+            value
+                .parse::<usize>()
+                .ok()
+                .and_then(|level| LevelFilter::iter().nth(level))
+        })
+        .ok_or(LogFilterError::BadLevel)
+}
+
+#[cfg(all(test, host))]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test]
+    fn the_most_specific_target_wins() {
+        let mut filters = LogFilters::new(LevelFilter::Info);
+        filters
+            .apply("warn, kalloc=debug,dev=trace,oxiv6_kernel::dev::virtio=2,spin=off")
+            .unwrap();
+        assert_eq!(filters.level("oxiv6_kernel::vm"), LevelFilter::Warn);
+        assert_eq!(filters.level("oxiv6_kernel::kalloc"), LevelFilter::Debug);
+        assert_eq!(filters.level("oxiv6_kernel::dev::spec"), LevelFilter::Trace);
+        assert_eq!(
+            filters.level("oxiv6_kernel::dev::virtio::block"),
+            LevelFilter::Warn
+        );
+        assert_eq!(filters.level("spin::mutex"), LevelFilter::Off);
+        // A prefix of a module's name doesn't cover it
+        assert_eq!(
+            filters.level("oxiv6_kernel::kalloc_trace"),
+            LevelFilter::Warn
+        );
+        assert_eq!(filters.max_level(), LevelFilter::Trace);
+        assert_eq!(
+            filters.to_string(),
+            "WARN,kalloc=DEBUG,dev=TRACE,dev::virtio=WARN,spin=OFF"
+        );
+        // The crate's name covers every kernel module without a filter of its own, and nothing else
+        filters.apply("oxiv6_kernel=error").unwrap();
+        assert_eq!(filters.level("oxiv6_kernel"), LevelFilter::Error);
+        assert_eq!(filters.level("oxiv6_kernel::vm"), LevelFilter::Error);
+        assert_eq!(filters.level("oxiv6_kernel::kalloc"), LevelFilter::Debug);
+        assert_eq!(filters.level("spin::mutex"), LevelFilter::Off);
+        assert_eq!(filters.level("virtio_drivers"), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn later_filters_replace_earlier_ones() {
+        let mut filters = LogFilters::new(LevelFilter::Info);
+        filters.apply("kalloc=debug").unwrap();
+        filters.apply("error,kalloc=trace").unwrap();
+        assert_eq!(filters.level("oxiv6_kernel::kalloc"), LevelFilter::Trace);
+        assert_eq!(filters.level("oxiv6_kernel::vm"), LevelFilter::Error);
+        assert_eq!(filters.to_string(), "ERROR,kalloc=TRACE");
+    }
+
+    #[test]
+    fn malformed_filters_change_nothing() {
+        let mut filters = LogFilters::new(LevelFilter::Info);
+        assert_eq!(
+            filters.apply("debug,vm=loud"),
+            Err(LogFilterError::BadLevel)
+        );
+        assert_eq!(
+            filters.apply("debug,=trace"),
+            Err(LogFilterError::BadTarget)
+        );
+        assert_eq!(filters.apply("6"), Err(LogFilterError::BadLevel));
+        let mut too_many = std::string::String::new();
+        for index in 0..=MAX_TARGET_FILTERS {
+            write!(too_many, "module{index}=debug,").unwrap();
+        }
+        assert_eq!(
+            filters.apply(&too_many),
+            Err(LogFilterError::TooManyTargets)
+        );
+        assert_eq!(filters.to_string(), "INFO");
+    }
+}
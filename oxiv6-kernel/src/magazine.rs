@@ -0,0 +1,172 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::slab::SLAB_CACHE_COUNT;
+use riscv::register::sstatus;
+
+/// The most free objects a magazine holds
+const MAGAZINE_SIZE: usize = 32;
+/// How many objects move between a magazine and its global pool at once
+pub(crate) const MAGAZINE_BATCH: usize = MAGAZINE_SIZE / 2;
+
+/// A stack of free pages or objects kept by one hart, so that most allocations and frees on that hart
+/// don't need the global locks
+pub(crate) struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    count: usize,
+}
+
+/// The magazines of one hart: one of free pages, and one for each slab cache
+pub(crate) struct HartCache {
+    pub(crate) pages: Magazine,
+    pub(crate) slabs: [Magazine; SLAB_CACHE_COUNT],
+}
+
+impl Magazine {
+    pub(crate) const fn new() -> Self {
+        Self {
+            objects: [core::ptr::null_mut(); MAGAZINE_SIZE],
+            count: 0,
+        }
+    }
+
+    /// Take a free object, first refilling the magazine with `refill` if it is empty
+    /// `refill` fills in as many objects as it can of the slice it is given, returning how many
+    /// Returns null if the magazine is empty and `refill` finds nothing
+    pub(crate) fn pop(&mut self, refill: impl FnOnce(&mut [*mut u8]) -> usize) -> *mut u8 {
+        if self.count == 0 {
+            self.count = refill(&mut self.objects[..MAGAZINE_BATCH]);
+            if self.count == 0 {
+                return core::ptr::null_mut();
+            }
+        }
+        self.count -= 1;
+        self.objects[self.count]
+    }
+
+    /// Keep a freed object, first handing a batch of objects to `flush` if the magazine is full
+    pub(crate) fn push(&mut self, object: *mut u8, flush: impl FnOnce(&[*mut u8])) {
+        if self.count == MAGAZINE_SIZE {
+            self.count -= MAGAZINE_BATCH;
+            flush(&self.objects[self.count..]);
+        }
+        self.objects[self.count] = object;
+        self.count += 1;
+    }
+
+    /// Is `object` already in the magazine, i.e. is it being freed twice?
+    pub(crate) fn contains(&self, object: *mut u8) -> bool {
+        self.objects[..self.count].contains(&object)
+    }
+
+    /// The number of objects in the magazine
+    pub(crate) fn len(&self) -> usize {
+        self.count
+    }
+}
+
+impl HartCache {
+    pub(crate) const fn new() -> Self {
+        Self {
+            pages: Magazine::new(),
+            slabs: [const { Magazine::new() }; SLAB_CACHE_COUNT],
+        }
+    }
+}
+
+/// Turns interrupts off on this hart until dropped, so that a hart's own magazines are never used by an
+/// interrupt handler while the hart is part way through using them
+pub(crate) struct InterruptsOff {
+    were_on: bool,
+}
+
+impl InterruptsOff {
+    #[cfg(not(host))]
+    pub(crate) fn new() -> Self {
+        let were_on = sstatus::read().sie();
+        unsafe { sstatus::clear_sie() };
+        Self { were_on }
+    }
+
+    /// Host tests have no interrupts to turn off
+    #[cfg(host)]
+    pub(crate) fn new() -> Self {
+        Self { were_on: false }
+    }
+}
+
+impl Drop for InterruptsOff {
+    fn drop(&mut self) {
+        if self.were_on {
+            unsafe { sstatus::set_sie() };
+        }
+    }
+}
+
+#[cfg(all(test, host))]
+mod tests {
+    use super::*;
+
+    fn object(index: usize) -> *mut u8 {
+        (0x1000 + index * 16) as *mut u8
+    }
+
+    #[test]
+    fn refills_and_flushes_in_batches() {
+        let mut magazine = Magazine::new();
+        let mut refills = 0;
+        let first = magazine.pop(|objects| {
+            refills += 1;
+            for (index, slot) in objects.iter_mut().enumerate() {
+                *slot = object(index);
+            }
+            objects.len()
+        });
+        assert_eq!(refills, 1);
+        assert_eq!(first, object(MAGAZINE_BATCH - 1));
+        assert_eq!(magazine.len(), MAGAZINE_BATCH - 1);
+
+        let mut flushed = 0;
+        for index in 0..=MAGAZINE_SIZE {
+            magazine.push(object(100 + index), |objects| {
+                assert_eq!(objects.len(), MAGAZINE_BATCH);
+                flushed += objects.len();
+            });
+        }
+        assert_eq!(flushed, MAGAZINE_BATCH);
+        assert_eq!(
+            magazine.len(),
+            MAGAZINE_BATCH - 1 + MAGAZINE_SIZE + 1 - MAGAZINE_BATCH
+        );
+    }
+
+    #[test]
+    fn empty_refill_gives_null() {
+        let mut magazine = Magazine::new();
+        assert!(magazine.pop(|_| 0).is_null());
+        assert_eq!(magazine.len(), 0);
+    }
+
+    #[test]
+    fn contains_only_held_objects() {
+        let mut magazine = Magazine::new();
+        magazine.push(object(1), |_| unreachable!());
+        assert!(magazine.contains(object(1)));
+        assert!(!magazine.contains(object(2)));
+        magazine.pop(|_| unreachable!());
+        assert!(!magazine.contains(object(1)));
+    }
+}
@@ -1,6 +1,12 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(host), no_std)]
+#![cfg_attr(not(host), no_main)]
+#![cfg_attr(host, allow(dead_code, unused_imports))]
 #![feature(naked_functions, asm_const)]
+#![cfg_attr(not(host), feature(alloc_error_handler))]
+// `cargo test` for the kernel target boots the kernel and runs the `#[test_case]`s in it, see `ktest`
+#![cfg_attr(all(test, not(host)), feature(custom_test_frameworks))]
+#![cfg_attr(all(test, not(host)), test_runner(crate::ktest::run_tests))]
+#![cfg_attr(all(test, not(host)), reexport_test_harness_main = "test_main")]
 
 /*!
    Copyright 2024 Claire Moore
@@ -18,38 +24,71 @@
    limitations under the License.
 */
 
-use crate::dev::spec::{get_cpu_count, get_physical_memory_size, load_fdt};
+use crate::dev::spec::{get_boot_arguments, get_cpu_count, get_physical_memory_size, load_fdt};
 use crate::println::println;
+#[cfg(not(host))]
 use core::arch::{asm, global_asm};
-use log::info;
+use log::{info, warn};
 
 const TRAPFRAME: usize = 4096;
 const STACK_SIZE: usize = 8192;
+// `kernelvec` finds a hart's trap stack by shifting its hartid
+const _: () = assert!(STACK_SIZE.is_power_of_two());
 const MAX_HART_COUNT: usize = 8;
+#[cfg(not(host))]
 static mut STACK_0: [[u8; STACK_SIZE]; MAX_HART_COUNT] = [[0; STACK_SIZE]; MAX_HART_COUNT];
 
 extern crate alloc;
 
+#[cfg(feature = "alloc-trace")]
+mod alloc_trace;
+mod backtrace;
 mod dev;
+mod dmesg;
+#[allow(dead_code)]
+mod file;
 mod kalloc;
+#[cfg(feature = "kasan")]
+mod kasan;
+#[cfg(all(test, not(host)))]
+mod ktest;
+mod log_filter;
+mod magazine;
 mod println;
 #[allow(dead_code)]
 mod proc;
+mod slab;
+mod symbols;
+#[allow(dead_code)]
+mod syscall;
+mod tlb;
+#[cfg(not(host))]
+mod trap;
 mod vm;
+#[allow(dead_code)]
+mod vma;
 
 extern "C" {
     // TODO: Understand why linker can't provide this as a usize
     pub(crate) fn etext();
+    pub(crate) fn erodata();
     pub(crate) fn end();
     pub(crate) fn trampoline();
+    // Host tests never boot, but still reference the entry point
+    #[cfg(host)]
+    pub(crate) fn _start();
 }
 
+#[cfg(not(host))]
 #[naked]
 #[no_mangle]
 #[link_section = ".text.entry"]
 pub(crate) unsafe extern "C" fn _start(hartid: usize, device_tree_paddr: usize) -> ! {
     unsafe {
         asm!(
+            "mv tp, a0",
+            // End the frame pointer chain for backtraces
+            "li s0, 0",
             "la sp, {stack0}",
             "li t0, {stack_size}",
             "addi t1, a0, 1",
@@ -64,11 +103,14 @@ pub(crate) unsafe extern "C" fn _start(hartid: usize, device_tree_paddr: usize)
     }
 }
 
+#[cfg(not(host))]
 #[naked]
 #[no_mangle]
 unsafe extern "C" fn subhart_start(hartid: usize, root_sp_location: usize) -> ! {
     unsafe {
         asm!(
+            "mv tp, a0",
+            "li s0, 0",
             "add sp, a1, zero",
             "j {rust_main}",
             rust_main = sym rust_main,
@@ -77,6 +119,7 @@ unsafe extern "C" fn subhart_start(hartid: usize, root_sp_location: usize) -> !
     }
 }
 
+#[cfg(not(host))]
 #[no_mangle]
 extern "C" fn rust_boot(hartid: usize, device_tree_paddr: usize) -> ! {
     if sbi_rt::probe_extension(sbi_rt::Console).is_available() {
@@ -87,6 +130,14 @@ extern "C" fn rust_boot(hartid: usize, device_tree_paddr: usize) -> ! {
     unsafe {
         load_fdt(device_tree_paddr);
     }
+    if let Some(filters) = get_boot_arguments().log_filters {
+        // Each is well formed, but together with LOG's they may name too many targets
+        if let Err(error) = println::apply_log_filters(filters) {
+            warn!("Ignoring loglevel={}: {:?}", filters, error);
+        }
+    }
+    crate::vm::select_paging_mode();
+    crate::dev::spec::load_memory_regions();
     info!(
         "end: 0x{:x}, etext: 0x{:x}, PHYSICAL_ADDRESS_STOP: 0x{:x}, CPU_COUNT: {}",
         end as usize,
@@ -97,12 +148,15 @@ extern "C" fn rust_boot(hartid: usize, device_tree_paddr: usize) -> ! {
 
     crate::kalloc::ALLOCATOR.init();
     info!("Allocator Initialized");
+    crate::dev::registry::init();
     crate::vm::kvmmake();
     info!("Set up Kernel page table");
+    crate::proc::procinit();
 
     rust_main(hartid)
 }
 
+#[cfg(not(host))]
 #[no_mangle]
 extern "C" fn rust_main(_hartid: usize) -> ! {
     crate::vm::KERNEL_PAGE_TABLE
@@ -110,18 +164,53 @@ extern "C" fn rust_main(_hartid: usize) -> ! {
         .expect("Expected kernel page table to be initialized")
         .set_as_active_table();
     info!("Installed Kernel page table");
+    crate::tlb::probe_asid_bits();
+    crate::trap::trapinithart();
+
+    #[cfg(test)]
+    test_main();
 
     sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::NoReason);
     #[allow(clippy::empty_loop)]
     loop {}
 }
 
+#[cfg(not(host))]
 global_asm!(include_str!("trampoline.S"), TRAPFRAME = const TRAPFRAME);
+#[cfg(not(host))]
+global_asm!(
+    include_str!("kernelvec.S"),
+    TRAP_STACKS = sym crate::trap::TRAP_STACKS,
+    STACK_SHIFT = const STACK_SIZE.trailing_zeros(),
+);
+
+/// Infallible allocations that fail end up here. Killing a process would not help, as nothing can wait for it to
+/// exit, so the kernel panics
+#[cfg(not(host))]
+#[alloc_error_handler]
+fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
+    let statistics = crate::kalloc::ALLOCATOR.memfree_count();
+    log::error!(
+        "Out of memory allocating {:?}, with {} bytes free: {:?}",
+        layout,
+        statistics.free_bytes(),
+        statistics
+    );
+    panic!("Out of memory allocating {layout:?}");
+}
 
-#[cfg(not(test))]
+#[cfg(not(host))]
 #[panic_handler]
 fn panic_handler(info: &core::panic::PanicInfo<'_>) -> ! {
+    #[cfg(test)]
+    println!("FAILED");
     println!("{}", info);
+    crate::backtrace::print_backtrace();
+    // After the panic itself, which a long log would scroll away and a busy one would hold up
+    crate::dmesg::dump();
+    if let Some(page_table) = crate::proc::myproc().and_then(|proc| proc.page_table()) {
+        page_table.dump();
+    }
     sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
     loop {}
 }
@@ -14,35 +14,55 @@
    limitations under the License.
 */
 
+use crate::log_filter::{LogFilterError, LogFilters};
+use crate::magazine::InterruptsOff;
 use core::fmt::Write;
+use log::LevelFilter;
+use spin::mutex::Mutex;
 
 static PRINT_IMPL: spin::once::Once<&'static dyn DebugPrint> = spin::once::Once::new();
-const LEVEL_FILTER: log::LevelFilter = log::LevelFilter::Info;
+/// The log filters the kernel was built with, from the `LOG` environment variable
+const BUILD_LOG_FILTERS: &str = env!("OXIV6_LOG");
+static LOG_FILTERS: Mutex<LogFilters> = Mutex::new(LogFilters::new(LevelFilter::Info));
 
-#[allow(unused_macros)]
 macro_rules! print {
-    ($($arg:tt)*) => { use core::fmt::Write; core::write!($crate::println::DebugWriter, $($arg)*).expect("Unable to write!"); }
+    ($($arg:tt)*) => {{ use core::fmt::Write; core::write!($crate::println::DebugWriter, $($arg)*).expect("Unable to write!"); }}
 }
 
 macro_rules! println {
-    ($($arg:tt)*) => { use core::fmt::Write; core::writeln!($crate::println::DebugWriter, $($arg)*).expect("Unable to write!"); }
+    ($($arg:tt)*) => {{ use core::fmt::Write; core::writeln!($crate::println::DebugWriter, $($arg)*).expect("Unable to write!"); }}
 }
 
+#[cfg(not(host))]
 #[inline]
 pub(crate) fn set_debug_console_print() {
     PRINT_IMPL.call_once(|| &DebugConsoleDebugPrint);
-    log::set_logger(&DebugWriter)
-        .map(|()| log::set_max_level(LEVEL_FILTER))
-        .expect("Unable to set logger");
+    set_logger();
 }
 
+#[cfg(not(host))]
 #[inline]
 pub(crate) fn set_legacy_debug_print() {
     PRINT_IMPL.call_once(|| &LegacyDebugPrint);
-    log::set_logger(&DebugWriter)
-        .map(|()| log::set_max_level(LEVEL_FILTER))
-        .expect("Unable to set logger");
+    set_logger();
 }
+
+#[cfg(not(host))]
+fn set_logger() {
+    log::set_logger(&DebugWriter).expect("Unable to set logger");
+    // build.rs checked these with the same parser
+    apply_log_filters(BUILD_LOG_FILTERS).expect("LOG has valid log filters");
+}
+
+/// Change which log records are printed, see [`LogFilters::apply`]
+pub(crate) fn apply_log_filters(filters: &str) -> Result<(), LogFilterError> {
+    let _interrupts_off = InterruptsOff::new();
+    let mut log_filters = LOG_FILTERS.lock();
+    log_filters.apply(filters)?;
+    log::set_max_level(log_filters.max_level());
+    Ok(())
+}
+
 trait DebugPrint: Sync {
     fn print_byte(&self, byte: u8) -> core::fmt::Result;
 
@@ -54,8 +74,10 @@ trait DebugPrint: Sync {
     }
 }
 
+#[cfg(not(host))]
 struct LegacyDebugPrint;
 
+#[cfg(not(host))]
 impl DebugPrint for LegacyDebugPrint {
     #[allow(deprecated)]
     fn print_byte(&self, byte: u8) -> core::fmt::Result {
@@ -67,8 +89,10 @@ impl DebugPrint for LegacyDebugPrint {
     }
 }
 
+#[cfg(not(host))]
 struct DebugConsoleDebugPrint;
 
+#[cfg(not(host))]
 impl DebugPrint for DebugConsoleDebugPrint {
     fn print_byte(&self, byte: u8) -> core::fmt::Result {
         if sbi_rt::console_write_byte(byte).is_ok() {
@@ -104,7 +128,9 @@ impl Write for DebugWriter {
 
 impl log::Log for DebugWriter {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
-        metadata.level() <= log::max_level()
+        // Interrupts are off so that a handler logging on this hart can't wait on the lock held here
+        let _interrupts_off = InterruptsOff::new();
+        metadata.level() <= LOG_FILTERS.lock().level(metadata.target())
     }
 
     fn log(&self, record: &log::Record) {
@@ -120,6 +146,7 @@ impl log::Log for DebugWriter {
                 line,
                 record.args()
             );
+            crate::dmesg::record(record);
         }
     }
 
@@ -1,10 +1,155 @@
-use crate::vm::PageTable;
-use spin::mutex::Mutex;
+use crate::file::File;
+use crate::vm::{trampoline_address, PageTable, PageTableEntryFlags, PAGE_SIZE};
+use crate::vma::{Backing, MapFlags, MmapError, Protection, VirtualMemoryAreas};
+use crate::MAX_HART_COUNT;
+use alloc::alloc::{alloc_zeroed, Layout};
+use alloc::sync::Arc;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{mutex::Mutex, once::Once};
+
+/// Maximum number of processes
+pub(crate) const NPROC: usize = 64;
+/// Maximum number of open files per process
+pub(crate) const NOFILE: usize = 16;
+/// Pages in each process's kernel stack
+pub(crate) const KSTACK_PAGES: usize = crate::STACK_SIZE / PAGE_SIZE;
+
+/// The process table
+pub(crate) static PROCS: Once<[Proc<'static>; NPROC]> = Once::new();
+
+/// The slot of the process running on each hart, plus one, or 0 if the hart is not running a process
+static CURRENT_SLOTS: [AtomicUsize; MAX_HART_COUNT] =
+    [const { AtomicUsize::new(0) }; MAX_HART_COUNT];
+
+/// The bottom of the kernel stack of the process in `slot`. Stacks are stacked down from the trampoline,
+/// each with an unmapped guard page beneath it, so that overflowing one faults instead of corrupting the next
+pub(crate) fn kstack(slot: usize) -> usize {
+    trampoline_address() - (slot + 1) * (KSTACK_PAGES + 1) * PAGE_SIZE + PAGE_SIZE
+}
+
+/// Allocate and map every process's kernel stack into the kernel page table
+/// # Panics
+/// Panics if memory runs out
+pub(crate) fn proc_mapstacks(page_table: &mut PageTable<'_>) {
+    let layout =
+        Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).expect("Unable to allocate for kernel stack");
+    for slot in 0..NPROC {
+        for page in 0..KSTACK_PAGES {
+            let stack_page = unsafe { alloc_zeroed(layout) };
+            assert!(!stack_page.is_null(), "proc_mapstacks: out of memory");
+            page_table
+                .map_pages(
+                    kstack(slot) + page * PAGE_SIZE,
+                    PAGE_SIZE,
+                    stack_page as usize,
+                    PageTableEntryFlags::RW,
+                )
+                .expect("Unable to map kernel stack");
+        }
+    }
+}
+
+/// Set up the process table, giving each process the kernel stack [`proc_mapstacks`] mapped for its slot
+pub(crate) fn procinit() {
+    PROCS.call_once(|| {
+        core::array::from_fn(|slot| Proc {
+            private_data: PrivateProcData {
+                kstack: kstack(slot),
+                ..PrivateProcData::default()
+            },
+            ..Proc::default()
+        })
+    });
+}
+
+/// The process running on this hart, if any
+pub(crate) fn myproc() -> Option<&'static Proc<'static>> {
+    let slot = CURRENT_SLOTS[cpuid()]
+        .load(Ordering::Acquire)
+        .checked_sub(1)?;
+    PROCS.get().map(|procs| &procs[slot])
+}
+
+/// Record which process slot this hart is running, for [`myproc`]
+pub(crate) fn set_current_slot(slot: Option<usize>) {
+    CURRENT_SLOTS[cpuid()].store(slot.map_or(0, |slot| slot + 1), Ordering::Release);
+}
+
+/// The live process with the pid `pid`, if any
+pub(crate) fn find_proc(pid: usize) -> Option<&'static Proc<'static>> {
+    PROCS.get()?.iter().find(|proc| {
+        let public_data = proc.public_data.lock();
+        public_data.pid == pid && !matches!(public_data.state, ProcState::Unused)
+    })
+}
+
+/// Kill the live process holding the most user memory, so that memory comes back once it exits
+/// Called once a fallible allocation of a user page or a page table page has given up. The victim is freed on its
+/// next return to user mode, see [`Proc::free_if_killed`], and is woken if it is sleeping so that it gets there
+/// Nothing is killed while an earlier victim is still exiting, or if no process holds any memory
+/// Returns the pid of the process killed, if any
+pub(crate) fn oom_kill() -> Option<usize> {
+    let procs = PROCS.get()?;
+    let mut victim: Option<(&Proc<'_>, usize)> = None;
+    for proc in procs {
+        // A process whose lock is held is left alone, as the allocation may be under that lock
+        let Some(public_data) = proc.public_data.try_lock() else {
+            continue;
+        };
+        if matches!(public_data.state, ProcState::Unused | ProcState::Zombie) {
+            continue;
+        }
+        if public_data.killed {
+            return None;
+        }
+        let memory_bytes = proc.memory_bytes.load(Ordering::Relaxed);
+        if memory_bytes > victim.map_or(0, |(_, most)| most) {
+            victim = Some((proc, memory_bytes));
+        }
+    }
+    let (proc, memory_bytes) = victim?;
+    let mut public_data = proc.public_data.try_lock()?;
+    public_data.killed = true;
+    if matches!(public_data.state, ProcState::Sleeping) {
+        public_data.state = ProcState::Runnable;
+    }
+    log::warn!(
+        "Out of memory: killed process {} ({}) holding {} bytes",
+        public_data.pid,
+        proc.name(),
+        memory_bytes
+    );
+    Some(public_data.pid)
+}
+
+/// The process whose kernel stack guard page holds `address`, if any
+pub(crate) fn kstack_guard_owner(address: usize) -> Option<&'static Proc<'static>> {
+    let slot = (trampoline_address() - 1).checked_sub(address)? / ((KSTACK_PAGES + 1) * PAGE_SIZE);
+    if slot >= NPROC || !(kstack(slot) - PAGE_SIZE..kstack(slot)).contains(&address) {
+        return None;
+    }
+    PROCS.get().map(|procs| &procs[slot])
+}
+
+/// The bounds of the kernel stack holding `address`, if it is in one. There are none until the process table is
+/// set up
+pub(crate) fn kstack_bounds(address: usize) -> Option<Range<usize>> {
+    PROCS.get()?;
+    let slot = (trampoline_address() - 1).checked_sub(address)? / ((KSTACK_PAGES + 1) * PAGE_SIZE);
+    if slot >= NPROC {
+        return None;
+    }
+    let stack = kstack(slot)..kstack(slot) + KSTACK_PAGES * PAGE_SIZE;
+    stack.contains(&address).then_some(stack)
+}
 
 #[derive(Debug, Default)]
 pub(crate) struct Proc<'a> {
     public_data: Mutex<PublicProcData>,
     private_data: PrivateProcData<'a>,
+    /// The user memory this process holds, kept outside `private_data` so that the OOM killer can read it
+    memory_bytes: AtomicUsize,
 }
 
 #[derive(Debug, Default)]
@@ -32,5 +177,163 @@ struct PrivateProcData<'a> {
     size: usize,
     tracing_mask: u32,
     page_table: Option<PageTable<'a>>,
+    memory_areas: VirtualMemoryAreas,
+    open_files: [Option<Arc<dyn File>>; NOFILE],
     name: &'a str,
 }
+
+/// The id of the hart this is running on, kept in `tp` since boot
+/// Must be called with interrupts disabled, to prevent moving to a different hart
+#[cfg(not(host))]
+#[inline]
+pub(crate) fn cpuid() -> usize {
+    let hartid: usize;
+    unsafe { core::arch::asm!("mv {}, tp", out(reg) hartid) };
+    hartid
+}
+
+/// Host tests run as if on hart 0
+#[cfg(host)]
+pub(crate) fn cpuid() -> usize {
+    0
+}
+
+impl<'a> Proc<'a> {
+    /// The name of this process
+    pub(crate) fn name(&self) -> &'a str {
+        self.private_data.name
+    }
+
+    /// This process's user page table, if it has one
+    pub(crate) fn page_table(&self) -> Option<&PageTable<'a>> {
+        self.private_data.page_table.as_ref()
+    }
+
+    /// Has the OOM killer chosen this process?
+    pub(crate) fn killed(&self) -> bool {
+        self.public_data.lock().killed
+    }
+
+    /// Free this process if the OOM killer has chosen it, returning whether it did. The process must be the one
+    /// running on this hart, which then runs no process
+    /// Every return to user mode must call this, after an interrupt such as the timer's as well as after a system
+    /// call, so that a victim spinning in user mode is freed too
+    pub(crate) fn free_if_killed(&mut self) -> bool {
+        if !self.killed() {
+            return false;
+        }
+        set_current_slot(None);
+        self.freeproc();
+        true
+    }
+
+    /// This process's pid, if its lock is free
+    pub(crate) fn try_pid(&self) -> Option<usize> {
+        self.public_data
+            .try_lock()
+            .map(|public_data| public_data.pid)
+    }
+
+    /// Look up an open file by its file descriptor
+    pub(crate) fn file(&self, file_descriptor: usize) -> Option<Arc<dyn File>> {
+        self.private_data
+            .open_files
+            .get(file_descriptor)
+            .cloned()
+            .flatten()
+    }
+
+    /// Map a new region into this process's address space
+    pub(crate) fn mmap(
+        &mut self,
+        address: usize,
+        length: usize,
+        protection: Protection,
+        flags: MapFlags,
+        backing: Backing,
+    ) -> Result<usize, MmapError> {
+        let private_data = &mut self.private_data;
+        let page_table = private_data
+            .page_table
+            .as_mut()
+            .ok_or(MmapError::InvalidArgument)?;
+        let mapped = private_data.memory_areas.mmap(
+            page_table,
+            private_data.size,
+            address,
+            length,
+            protection,
+            flags,
+            backing,
+        );
+        self.update_memory_bytes();
+        mapped
+    }
+
+    /// Unmap a region of this process's address space
+    pub(crate) fn munmap(&mut self, address: usize, length: usize) -> Result<(), MmapError> {
+        let private_data = &mut self.private_data;
+        let page_table = private_data
+            .page_table
+            .as_mut()
+            .ok_or(MmapError::InvalidArgument)?;
+        let unmapped = private_data
+            .memory_areas
+            .munmap(page_table, address, length);
+        self.update_memory_bytes();
+        unmapped
+    }
+
+    /// Change the protection of a region of this process's address space
+    pub(crate) fn mprotect(
+        &mut self,
+        address: usize,
+        length: usize,
+        protection: Protection,
+    ) -> Result<(), MmapError> {
+        let private_data = &mut self.private_data;
+        let page_table = private_data
+            .page_table
+            .as_mut()
+            .ok_or(MmapError::InvalidArgument)?;
+        private_data
+            .memory_areas
+            .mprotect(page_table, address, length, protection)
+    }
+
+    /// Release every memory mapping of this process, writing back shared file mappings
+    /// Must be called as the process exits, before its page table is freed
+    pub(crate) fn release_mappings(&mut self) {
+        let private_data = &mut self.private_data;
+        if let Some(page_table) = private_data.page_table.as_mut() {
+            private_data.memory_areas.unmap_all(page_table);
+        }
+        self.update_memory_bytes();
+    }
+
+    /// Recount the user memory this process holds, for the OOM killer
+    fn update_memory_bytes(&self) {
+        let private_data = &self.private_data;
+        self.memory_bytes.store(
+            private_data.size + private_data.memory_areas.mapped_bytes(),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Free everything this process owns and return its slot to the table, as xv6's `freeproc` does
+    /// Must be called once the process has exited and is no longer running
+    pub(crate) fn freeproc(&mut self) {
+        self.release_mappings();
+        let private_data = &mut self.private_data;
+        *private_data = PrivateProcData {
+            kstack: private_data.kstack,
+            ..PrivateProcData::default()
+        };
+        #[cfg(feature = "alloc-trace")]
+        {
+            let pid = self.public_data.lock().pid;
+            crate::alloc_trace::report_leaks(pid);
+        }
+        *self.public_data.lock() = PublicProcData::default();
+    }
+}
@@ -0,0 +1,488 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::vm::PAGE_SIZE;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::cell::Cell;
+use core::ptr::{self, NonNull};
+use spin::mutex::Mutex;
+
+/// The number of slab caches, one for each power of two object size from 16 bytes to 2 KiB
+pub(crate) const SLAB_CACHE_COUNT: usize = 8;
+/// Enough bitmap words for the most objects a slab can hold, 16 byte objects in a page
+const SLAB_BITMAP_WORDS: usize = PAGE_SIZE / 16 / 64;
+/// Marks the start of a live slab, so that freeing a pointer that isn't in one is caught
+const SLAB_MAGIC: u64 = 0x5ab0_ca4e_0b1e_c75e;
+/// The byte written after the end of every object, up to the size of its cache
+const CANARY_BYTE: u8 = 0xca;
+/// The most canary bytes checked after an object, so large objects in a much larger cache stay cheap to free
+const MAX_CANARY_SIZE: usize = 64;
+
+/// The start of every slab, followed by its objects. Slabs are aligned to their own size, so the slab an
+/// object belongs to is found by rounding its address down
+#[repr(C)]
+struct SlabHeader {
+    magic: u64,
+    next: Cell<Option<NonNull<SlabHeader>>>,
+    prev: Cell<Option<NonNull<SlabHeader>>>,
+    /// The number of objects handed out from this slab
+    in_use: usize,
+    /// A set bit for every object handed out
+    bitmap: [u64; SLAB_BITMAP_WORDS],
+}
+
+/// The slabs of a cache that have a free object. Full slabs are not tracked until an object in them is freed
+struct SlabList {
+    partial: Option<NonNull<SlabHeader>>,
+    slab_count: usize,
+    objects_in_use: usize,
+}
+
+/// A cache of equally sized objects, carved out of slabs from the page allocator
+/// Free objects are found through each slab's bitmap rather than a free list, and as every object in a cache is the
+/// same size there are no neighbors to coalesce. A slab that empties goes back to the page allocator instead, which
+/// merges it with its free buddies
+pub(crate) struct SlabCache {
+    object_size: usize,
+    slab_order: usize,
+    slabs: Mutex<SlabList>,
+}
+
+/// A snapshot of how much of a [`SlabCache`] is in use
+#[allow(dead_code)]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct SlabCacheStatistics {
+    pub(crate) object_size: usize,
+    pub(crate) slab_count: usize,
+    pub(crate) objects_in_use: usize,
+    pub(crate) objects_free: usize,
+}
+
+unsafe impl Sync for SlabCache {}
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    /// A cache of `object_size` objects, in slabs of `2^slab_order` pages. Larger objects should use larger
+    /// slabs, so that the header wastes at most an object or so per slab
+    pub(crate) const fn new(object_size: usize, slab_order: usize) -> Self {
+        Self {
+            object_size,
+            slab_order,
+            slabs: Mutex::new(SlabList {
+                partial: None,
+                slab_count: 0,
+                objects_in_use: 0,
+            }),
+        }
+    }
+
+    pub(crate) fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    /// Can an object from this cache hold `layout`?
+    pub(crate) fn fits(&self, layout: Layout) -> bool {
+        core::cmp::max(layout.size(), layout.align()) <= self.object_size
+    }
+
+    /// Allocate up to `objects.len()` objects into `objects` under one lock, taking new slabs from
+    /// `page_allocator` as the others fill up
+    /// Returns how many were allocated, which is fewer than asked for only if memory runs out
+    pub(crate) fn alloc_batch(
+        &self,
+        page_allocator: &impl GlobalAlloc,
+        objects: &mut [*mut u8],
+    ) -> usize {
+        let mut slabs = self.slabs.lock();
+        for (count, object) in objects.iter_mut().enumerate() {
+            *object = self.alloc_locked(&mut slabs, page_allocator);
+            if object.is_null() {
+                return count;
+            }
+        }
+        objects.len()
+    }
+
+    /// Free every object in `objects` under one lock, giving slabs back to `page_allocator` as they empty
+    /// # Safety
+    /// Every object must have been allocated from this cache, and not freed since
+    pub(crate) unsafe fn dealloc_batch(
+        &self,
+        page_allocator: &impl GlobalAlloc,
+        objects: &[*mut u8],
+    ) {
+        let mut slabs = self.slabs.lock();
+        for &object in objects {
+            unsafe { self.dealloc_locked(&mut slabs, page_allocator, object) };
+        }
+    }
+
+    /// Allocate an object, taking a new slab from `page_allocator` if every slab is full
+    /// Returns null if there is no memory left
+    fn alloc_locked(&self, slabs: &mut SlabList, page_allocator: &impl GlobalAlloc) -> *mut u8 {
+        let slab = if let Some(slab) = slabs.partial {
+            slab
+        } else {
+            let Some(slab) = self.new_slab(page_allocator) else {
+                return ptr::null_mut();
+            };
+            Self::push(slabs, slab);
+            slabs.slab_count += 1;
+            slab
+        };
+
+        let header = unsafe { slab.as_ptr().as_mut() }.unwrap();
+        let index = header
+            .bitmap
+            .iter()
+            .enumerate()
+            .find_map(|(word, bits)| {
+                (*bits != u64::MAX).then(|| word * 64 + bits.trailing_ones() as usize)
+            })
+            .filter(|&index| index < self.objects_per_slab())
+            .expect("KSA_alloc: Partial slab has no free objects");
+        header.bitmap[index / 64] |= 1 << (index % 64);
+        header.in_use += 1;
+        slabs.objects_in_use += 1;
+        if header.in_use == self.objects_per_slab() {
+            Self::remove(slabs, slab);
+        }
+        unsafe {
+            slab.as_ptr()
+                .cast::<u8>()
+                .add(self.object_offset() + index * self.object_size)
+        }
+    }
+
+    /// Free the object at `ptr`, giving its slab back to `page_allocator` if it is now empty
+    /// # Safety
+    /// `ptr` must have been allocated from this cache, and not freed since
+    unsafe fn dealloc_locked(
+        &self,
+        slabs: &mut SlabList,
+        page_allocator: &impl GlobalAlloc,
+        ptr: *mut u8,
+    ) {
+        let slab_address = ptr as usize & !(self.slab_size() - 1);
+        let offset = (ptr as usize - slab_address)
+            .checked_sub(self.object_offset())
+            .filter(|offset| offset % self.object_size == 0)
+            .expect("KSA_dealloc: Out of bounds");
+        let index = offset / self.object_size;
+
+        let slab = unsafe { NonNull::new_unchecked(slab_address as *mut SlabHeader) };
+        let header = unsafe { slab.as_ptr().as_mut() }.unwrap();
+        assert!(header.magic == SLAB_MAGIC, "KSA_dealloc: Not in a slab");
+        assert!(
+            header.bitmap[index / 64] & (1 << (index % 64)) != 0,
+            "KSA_dealloc: Double free"
+        );
+        let was_full = header.in_use == self.objects_per_slab();
+        header.bitmap[index / 64] &= !(1 << (index % 64));
+        header.in_use -= 1;
+        slabs.objects_in_use -= 1;
+
+        if header.in_use == 0 {
+            if !was_full {
+                Self::remove(slabs, slab);
+            }
+            slabs.slab_count -= 1;
+            header.magic = 0;
+            unsafe { page_allocator.dealloc(slab_address as *mut u8, self.slab_layout()) };
+        } else if was_full {
+            Self::push(slabs, slab);
+        }
+    }
+
+    /// Fill the slack after the first `size` bytes of `object` with canary bytes, to be checked by
+    /// [`SlabCache::canary_intact`] when it is freed or resized
+    /// # Safety
+    /// `object` must have been allocated from this cache, and not freed since
+    pub(crate) unsafe fn write_canary(&self, object: *mut u8, size: usize) {
+        let canary_size = self.canary_size(size);
+        unsafe { object.add(size).write_bytes(CANARY_BYTE, canary_size) };
+    }
+
+    /// Have the canary bytes after the first `size` bytes of `object` survived, i.e. has nothing written
+    /// past the end of it?
+    /// # Safety
+    /// `object` must have been allocated from this cache, and its canary written for `size`
+    pub(crate) unsafe fn canary_intact(&self, object: *mut u8, size: usize) -> bool {
+        let canary_size = self.canary_size(size);
+        unsafe { core::slice::from_raw_parts(object.add(size), canary_size) }
+            .iter()
+            .all(|&byte| byte == CANARY_BYTE)
+    }
+
+    fn canary_size(&self, size: usize) -> usize {
+        core::cmp::min(self.object_size - size, MAX_CANARY_SIZE)
+    }
+
+    pub(crate) fn statistics(&self) -> SlabCacheStatistics {
+        let slabs = self.slabs.lock();
+        SlabCacheStatistics {
+            object_size: self.object_size,
+            slab_count: slabs.slab_count,
+            objects_in_use: slabs.objects_in_use,
+            objects_free: slabs.slab_count * self.objects_per_slab() - slabs.objects_in_use,
+        }
+    }
+
+    /// Take a block from `page_allocator` and lay it out as an empty slab
+    fn new_slab(&self, page_allocator: &impl GlobalAlloc) -> Option<NonNull<SlabHeader>> {
+        let slab =
+            NonNull::new(unsafe { page_allocator.alloc(self.slab_layout()) })?.cast::<SlabHeader>();
+        unsafe {
+            slab.as_ptr().write(SlabHeader {
+                magic: SLAB_MAGIC,
+                next: Cell::new(None),
+                prev: Cell::new(None),
+                in_use: 0,
+                bitmap: [0; SLAB_BITMAP_WORDS],
+            });
+        }
+        Some(slab)
+    }
+
+    fn push(slabs: &mut SlabList, slab: NonNull<SlabHeader>) {
+        let header = unsafe { slab.as_ref() };
+        header.next.set(slabs.partial);
+        header.prev.set(None);
+        if let Some(next) = slabs.partial {
+            unsafe { next.as_ref() }.prev.set(Some(slab));
+        }
+        slabs.partial = Some(slab);
+    }
+
+    fn remove(slabs: &mut SlabList, slab: NonNull<SlabHeader>) {
+        let header = unsafe { slab.as_ref() };
+        match header.prev.get() {
+            Some(prev) => unsafe { prev.as_ref() }.next.set(header.next.get()),
+            None => slabs.partial = header.next.get(),
+        }
+        if let Some(next) = header.next.get() {
+            unsafe { next.as_ref() }.prev.set(header.prev.get());
+        }
+    }
+
+    fn slab_size(&self) -> usize {
+        PAGE_SIZE << self.slab_order
+    }
+
+    fn slab_layout(&self) -> Layout {
+        unsafe { Layout::from_size_align_unchecked(self.slab_size(), self.slab_size()) }
+    }
+
+    /// Where the first object starts, after the header and aligned to the object size
+    fn object_offset(&self) -> usize {
+        core::mem::size_of::<SlabHeader>().next_multiple_of(self.object_size)
+    }
+
+    fn objects_per_slab(&self) -> usize {
+        core::cmp::min(
+            (self.slab_size() - self.object_offset()) / self.object_size,
+            SLAB_BITMAP_WORDS * 64,
+        )
+    }
+}
+
+#[cfg(all(test, host))]
+mod tests {
+    use super::*;
+    use crate::kalloc::tests::{allocator, Arena};
+    use crate::kalloc::MemoryStatistics;
+    use crate::kalloc::Random;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::alloc::System;
+    use std::vec::Vec;
+
+    /// Hands out slabs from the host allocator, counting how many are outstanding
+    #[derive(Default)]
+    struct HostPages {
+        outstanding: AtomicUsize,
+    }
+
+    unsafe impl GlobalAlloc for HostPages {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.outstanding.fetch_add(1, Ordering::Relaxed);
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.outstanding.fetch_sub(1, Ordering::Relaxed);
+            unsafe { System.dealloc(ptr, layout) };
+        }
+    }
+
+    struct Object {
+        ptr: *mut u8,
+        size: usize,
+        tag: u8,
+    }
+
+    impl Object {
+        fn layout(&self) -> Layout {
+            Layout::from_size_align(self.size, 1).unwrap()
+        }
+
+        fn contents(&self) -> &[u8] {
+            unsafe { core::slice::from_raw_parts(self.ptr, self.size) }
+        }
+
+        /// Fill the object with `tag`, up to its canary
+        fn fill(&mut self, tag: u8) {
+            unsafe { self.ptr.write_bytes(tag, self.size) };
+            self.tag = tag;
+        }
+    }
+
+    /// The object size of the slab cache `size` bytes come from
+    fn size_class(statistics: &MemoryStatistics, size: usize) -> usize {
+        statistics
+            .slab_caches
+            .iter()
+            .map(|slab_cache| slab_cache.object_size)
+            .find(|&object_size| object_size >= size)
+            .unwrap()
+    }
+
+    /// Objects the slab caches count as in use that aren't waiting in a magazine, in bytes
+    fn bytes_in_use(statistics: &MemoryStatistics) -> usize {
+        statistics
+            .slab_caches
+            .iter()
+            .map(|slab_cache| slab_cache.objects_in_use * slab_cache.object_size)
+            .sum::<usize>()
+            - statistics.magazine_bytes
+    }
+
+    /// Random allocations, frees and reallocations through the kernel allocator, whose realloc keeps objects in
+    /// place within a size class. The allocator checks each object's canary when it is reallocated or freed
+    #[test]
+    fn random_alloc_free_realloc() {
+        let arena = Arena::new();
+        let regions = [arena.region()];
+        let allocator = allocator(&arena, &regions);
+        let mut random = Random::new();
+        let mut live: Vec<Object> = Vec::new();
+
+        for step in 0..20_000_usize {
+            let tag = u8::try_from(step % 251).unwrap();
+            match random.below(8) {
+                0..=3 if live.len() < 512 => {
+                    let mut object = Object {
+                        ptr: ptr::null_mut(),
+                        size: 1 + random.below(2048),
+                        tag,
+                    };
+                    object.ptr = unsafe { allocator.alloc(object.layout()) };
+                    assert!(!object.ptr.is_null());
+                    object.fill(tag);
+                    live.push(object);
+                }
+                4..=5 if !live.is_empty() => {
+                    let object = live.swap_remove(random.below(live.len()));
+                    assert!(object.contents().iter().all(|&byte| byte == object.tag));
+                    unsafe { allocator.dealloc(object.ptr, object.layout()) };
+                }
+                6..=7 if !live.is_empty() => {
+                    let index = random.below(live.len());
+                    let object = &mut live[index];
+                    let new_size = 1 + random.below(2048);
+                    let statistics = allocator.memfree_count();
+                    let same_class =
+                        size_class(&statistics, object.size) == size_class(&statistics, new_size);
+                    let ptr = unsafe { allocator.realloc(object.ptr, object.layout(), new_size) };
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr == object.ptr, same_class);
+                    let kept = object.size.min(new_size);
+                    object.ptr = ptr;
+                    object.size = new_size;
+                    assert!(object.contents()[..kept]
+                        .iter()
+                        .all(|&byte| byte == object.tag));
+                    object.fill(tag);
+                }
+                _ => {}
+            }
+
+            let statistics = allocator.memfree_count();
+            let live_bytes: usize = live
+                .iter()
+                .map(|object| size_class(&statistics, object.size))
+                .sum();
+            assert_eq!(bytes_in_use(&statistics), live_bytes);
+        }
+
+        for object in live.drain(..) {
+            assert!(object.contents().iter().all(|&byte| byte == object.tag));
+            unsafe { allocator.dealloc(object.ptr, object.layout()) };
+        }
+        assert_eq!(bytes_in_use(&allocator.memfree_count()), 0);
+    }
+
+    #[test]
+    fn slabs_are_reused_before_new_ones_are_taken() {
+        let cache = SlabCache::new(64, 0);
+        let pages = HostPages::default();
+        let mut objects = [ptr::null_mut(); 16];
+        assert_eq!(cache.alloc_batch(&pages, &mut objects), objects.len());
+        unsafe { cache.dealloc_batch(&pages, &objects[..8]) };
+        let mut again = [ptr::null_mut(); 8];
+        assert_eq!(cache.alloc_batch(&pages, &mut again), again.len());
+        assert_eq!(cache.statistics().slab_count, 1);
+        assert_eq!(pages.outstanding.load(Ordering::Relaxed), 1);
+        for object in again {
+            assert!(objects[..8].contains(&object));
+        }
+    }
+
+    #[test]
+    fn canary_catches_overrun() {
+        let cache = SlabCache::new(32, 0);
+        let pages = HostPages::default();
+        let mut object = [ptr::null_mut()];
+        cache.alloc_batch(&pages, &mut object);
+        let object = object[0];
+        unsafe {
+            cache.write_canary(object, 20);
+            assert!(cache.canary_intact(object, 20));
+            object.add(20).write(0);
+            assert!(!cache.canary_intact(object, 20));
+            // A full object has no room for a canary, so can't be caught
+            assert!(cache.canary_intact(object, 32));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Double free")]
+    fn double_free_panics() {
+        let cache = SlabCache::new(16, 0);
+        let pages = HostPages::default();
+        let mut objects = [ptr::null_mut(); 2];
+        cache.alloc_batch(&pages, &mut objects);
+        unsafe { cache.dealloc_batch(&pages, &[objects[0], objects[0]]) };
+    }
+
+    #[test]
+    #[should_panic(expected = "Not in a slab")]
+    fn free_outside_slab_panics() {
+        let cache = SlabCache::new(16, 0);
+        let pages = HostPages::default();
+        let block = unsafe { System.alloc_zeroed(cache.slab_layout()) };
+        unsafe { cache.dealloc_batch(&pages, &[block.add(cache.object_offset())]) };
+    }
+}
@@ -0,0 +1,258 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! The kernel's own symbol table, to name the functions in backtraces.
+//! The linked kernel has room for the table in its `.kernel_symbols` section, which `cargo xtask` fills in from
+//! the ELF's symbols after linking, without moving anything. A kernel built without xtask has an empty table,
+//! and prints bare addresses.
+//!
+//! The table is little endian: a [`Header`], then `count` [`Entry`]s sorted by address, then the names
+//! they point into, demangled
+
+use core::fmt;
+
+/// Room for the symbol table, enough for the names of every function in a debug build
+const SYMBOL_SPACE: usize = 1024 * 1024;
+/// Starts a filled in symbol table, "SYM1"
+const SYMBOL_MAGIC: u32 = 0x314d_5953;
+
+#[repr(C, align(8))]
+struct SymbolSpace([u8; SYMBOL_SPACE]);
+
+#[used]
+#[link_section = ".kernel_symbols"]
+static KERNEL_SYMBOLS: SymbolSpace = SymbolSpace([0; SYMBOL_SPACE]);
+
+/// The start of the symbol table
+#[repr(C)]
+struct Header {
+    magic: u32,
+    count: u32,
+    names_length: u32,
+    _reserved: u32,
+}
+
+/// A function, and where its name is
+#[repr(C)]
+struct Entry {
+    address: u64,
+    size: u32,
+    name_offset: u32,
+}
+
+/// A symbol table, checked to be well formed
+struct SymbolTable<'a> {
+    entries: &'a [Entry],
+    names: &'a [u8],
+}
+
+/// The function holding an address, and how far into it the address is
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Symbol<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) offset: usize,
+}
+
+/// An address, shown with the function it is in if the symbol table has it
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Location(pub(crate) usize);
+
+/// Return addresses, shown as [`Location`]s innermost first, stopping at the first zero
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Locations<'a>(pub(crate) &'a [usize]);
+
+impl<'a> SymbolTable<'a> {
+    /// Check that `bytes` holds a whole symbol table
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let header_size = core::mem::size_of::<Header>();
+        if bytes.len() < header_size
+            || !(bytes.as_ptr() as usize).is_multiple_of(core::mem::align_of::<Entry>())
+        {
+            return None;
+        }
+        // Checked to be aligned above
+        #[allow(clippy::cast_ptr_alignment)]
+        let header = unsafe { &*bytes.as_ptr().cast::<Header>() };
+        if header.magic != SYMBOL_MAGIC {
+            return None;
+        }
+        let count = usize::try_from(header.count).ok()?;
+        let entries_end =
+            header_size.checked_add(count.checked_mul(core::mem::size_of::<Entry>())?)?;
+        let names_end = entries_end.checked_add(usize::try_from(header.names_length).ok()?)?;
+        if names_end > bytes.len() {
+            return None;
+        }
+        #[allow(clippy::cast_ptr_alignment)]
+        let entries = unsafe {
+            core::slice::from_raw_parts(bytes[header_size..].as_ptr().cast::<Entry>(), count)
+        };
+        Some(Self {
+            entries,
+            names: &bytes[entries_end..names_end],
+        })
+    }
+
+    /// The function holding `address`
+    fn lookup(&self, address: usize) -> Option<Symbol<'a>> {
+        let address = u64::try_from(address).ok()?;
+        let index = self
+            .entries
+            .partition_point(|entry| entry.address <= address)
+            .checked_sub(1)?;
+        let entry = &self.entries[index];
+        let offset = address - entry.address;
+        if offset >= u64::from(entry.size) {
+            return None;
+        }
+        let name_start = usize::try_from(entry.name_offset).ok()?;
+        let name_end = self
+            .entries
+            .get(index + 1)
+            .map_or(Some(self.names.len()), |next| {
+                usize::try_from(next.name_offset).ok()
+            })?;
+        Some(Symbol {
+            name: core::str::from_utf8(self.names.get(name_start..name_end)?).ok()?,
+            offset: usize::try_from(offset).ok()?,
+        })
+    }
+}
+
+/// The function in the kernel holding `address`, if the symbol table has been filled in
+pub(crate) fn lookup(address: usize) -> Option<Symbol<'static>> {
+    // The table is filled in after compiling, so its zeroes must not be assumed
+    let symbols = core::hint::black_box(core::ptr::addr_of!(KERNEL_SYMBOLS.0));
+    SymbolTable::parse(unsafe { &*symbols })?.lookup(address)
+}
+
+impl fmt::Display for Location {
+    /// Show a return address as `0x80201234 kernel::function+0x34`. The call is just before the return address,
+    /// which may be the start of the next function, so the function is found from the byte before it
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:x}", self.0)?;
+        if let Some(symbol) = self.0.checked_sub(1).and_then(lookup) {
+            write!(f, " {}+0x{:x}", symbol.name, symbol.offset + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Locations<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, &address) in self
+            .0
+            .iter()
+            .take_while(|&&address| address != 0)
+            .enumerate()
+        {
+            if index > 0 {
+                f.write_str(" <- ")?;
+            }
+            write!(f, "{}", Location(address))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, host))]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// A symbol table of `(address, size, name)`s, which must be sorted by address, as xtask writes it
+    fn table(symbols: &[(u64, u32, &str)]) -> Vec<u64> {
+        let mut bytes = Vec::new();
+        let names_length: usize = symbols.iter().map(|(_, _, name)| name.len()).sum();
+        for field in [
+            SYMBOL_MAGIC,
+            u32::try_from(symbols.len()).unwrap(),
+            u32::try_from(names_length).unwrap(),
+            0,
+        ] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        let mut name_offset = 0;
+        for (address, size, name) in symbols {
+            bytes.extend_from_slice(&address.to_le_bytes());
+            bytes.extend_from_slice(&size.to_le_bytes());
+            bytes.extend_from_slice(&u32::try_from(name_offset).unwrap().to_le_bytes());
+            name_offset += name.len();
+        }
+        for (_, _, name) in symbols {
+            bytes.extend_from_slice(name.as_bytes());
+        }
+        // Kept in u64s, so the table is aligned like the kernel's
+        bytes
+            .chunks(8)
+            .map(|chunk| {
+                let mut word = [0; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u64::from_ne_bytes(word)
+            })
+            .collect()
+    }
+
+    fn bytes(words: &[u64]) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(words.as_ptr().cast(), words.len() * 8) }
+    }
+
+    #[test]
+    fn finds_the_function_holding_an_address() {
+        let words = table(&[
+            (0x8020_0000, 0x40, "_start"),
+            (0x8020_0100, 0x20, "oxiv6_kernel::rust_main"),
+            (0x8020_0120, 0x100, "core::panicking::panic"),
+        ]);
+        let symbols = SymbolTable::parse(bytes(&words)).unwrap();
+        assert_eq!(
+            symbols.lookup(0x8020_0000),
+            Some(Symbol {
+                name: "_start",
+                offset: 0
+            })
+        );
+        assert_eq!(
+            symbols.lookup(0x8020_011f),
+            Some(Symbol {
+                name: "oxiv6_kernel::rust_main",
+                offset: 0x1f
+            })
+        );
+        assert_eq!(
+            symbols.lookup(0x8020_0121).map(|symbol| symbol.name),
+            Some("core::panicking::panic")
+        );
+        // Before the first function, in a gap between functions, and past the last one
+        assert_eq!(symbols.lookup(0x8000_0000), None);
+        assert_eq!(symbols.lookup(0x8020_0040), None);
+        assert_eq!(symbols.lookup(0x8020_0220), None);
+    }
+
+    #[test]
+    fn rejects_empty_and_truncated_tables() {
+        assert!(SymbolTable::parse(bytes(&[0; 8])).is_none());
+        let words = table(&[(0x8020_0000, 0x40, "_start")]);
+        assert!(SymbolTable::parse(&bytes(&words)[..bytes(&words).len() - 8]).is_none());
+    }
+
+    #[test]
+    fn the_unfilled_kernel_table_is_empty() {
+        assert_eq!(lookup(0x8020_1234), None);
+        assert_eq!(Location(0x8020_1234).to_string(), "0x80201234");
+    }
+}
@@ -0,0 +1,189 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::println::apply_log_filters;
+use crate::proc::{find_proc, Proc};
+use crate::vm::PageTable;
+use crate::vma::{Backing, MapFlags, Protection};
+use num_enum::TryFromPrimitive;
+
+/// System call numbers, continuing on from xv6's numbering
+#[repr(usize)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone, TryFromPrimitive)]
+pub(crate) enum Syscall {
+    Mmap = 22,
+    Munmap = 23,
+    Mprotect = 24,
+    Vmprint = 25,
+    Allocdump = 26,
+    Setloglevel = 27,
+    Dmesg = 28,
+}
+
+/// The value returned to user space when a system call fails
+const SYSCALL_ERROR: usize = usize::MAX;
+/// The longest log filters `setloglevel` takes
+const MAX_LOG_FILTERS_LENGTH: usize = 256;
+
+/// Run the system call `number` for `proc` with the arguments from `a0`-`a5`, returning the value for `a0`
+/// A process the OOM killer has chosen runs no more system calls, and is freed as its system call returns. The
+/// hart then runs no process, so the trap path must check [`crate::proc::myproc`] before returning to user space
+pub(crate) fn syscall(proc: &mut Proc<'_>, number: usize, arguments: [usize; 6]) -> usize {
+    let result = if proc.killed() {
+        SYSCALL_ERROR
+    } else {
+        dispatch(proc, number, arguments)
+    };
+    proc.free_if_killed();
+    result
+}
+
+/// Run the system call `number`
+fn dispatch(proc: &mut Proc<'_>, number: usize, arguments: [usize; 6]) -> usize {
+    match Syscall::try_from(number) {
+        Ok(Syscall::Mmap) => sys_mmap(proc, arguments),
+        Ok(Syscall::Munmap) => sys_munmap(proc, arguments),
+        Ok(Syscall::Mprotect) => sys_mprotect(proc, arguments),
+        Ok(Syscall::Vmprint) => sys_vmprint(proc, arguments),
+        Ok(Syscall::Allocdump) => sys_allocdump(),
+        Ok(Syscall::Setloglevel) => sys_setloglevel(proc, arguments),
+        Ok(Syscall::Dmesg) => sys_dmesg(proc, arguments),
+        Err(_) => {
+            log::warn!("Unknown syscall {}", number);
+            SYSCALL_ERROR
+        }
+    }
+}
+
+/// `void *mmap(void *addr, size_t length, int prot, int flags, int fd, off_t offset)`
+fn sys_mmap(proc: &mut Proc<'_>, arguments: [usize; 6]) -> usize {
+    let [address, length, protection, flags, file_descriptor, offset] = arguments;
+    let (Some(protection), Some(flags)) = (
+        u8::try_from(protection)
+            .ok()
+            .and_then(Protection::from_bits),
+        u32::try_from(flags).ok().and_then(MapFlags::from_bits),
+    ) else {
+        return SYSCALL_ERROR;
+    };
+    let backing = if flags.contains(MapFlags::ANONYMOUS) {
+        Backing::Anonymous
+    } else if let Some(file) = proc.file(file_descriptor) {
+        Backing::File { file, offset }
+    } else {
+        return SYSCALL_ERROR;
+    };
+
+    proc.mmap(address, length, protection, flags, backing)
+        .unwrap_or(SYSCALL_ERROR)
+}
+
+/// `int munmap(void *addr, size_t length)`
+fn sys_munmap(proc: &mut Proc<'_>, arguments: [usize; 6]) -> usize {
+    let [address, length, ..] = arguments;
+    proc.munmap(address, length).map_or(SYSCALL_ERROR, |()| 0)
+}
+
+/// `int mprotect(void *addr, size_t len, int prot)`
+fn sys_mprotect(proc: &mut Proc<'_>, arguments: [usize; 6]) -> usize {
+    let [address, length, protection, ..] = arguments;
+    let Some(protection) = u8::try_from(protection)
+        .ok()
+        .and_then(Protection::from_bits)
+    else {
+        return SYSCALL_ERROR;
+    };
+    proc.mprotect(address, length, protection)
+        .map_or(SYSCALL_ERROR, |()| 0)
+}
+
+/// `int vmprint(int pid)`, printing the page table of process `pid`, or of the caller if `pid` is 0
+fn sys_vmprint(proc: &mut Proc<'_>, arguments: [usize; 6]) -> usize {
+    let [pid, ..] = arguments;
+    let printed = if pid == 0 {
+        proc.page_table().map(PageTable::dump)
+    } else {
+        find_proc(pid)
+            .and_then(|proc| proc.page_table())
+            .map(PageTable::dump)
+    };
+    printed.map_or(SYSCALL_ERROR, |()| 0)
+}
+
+/// `int allocdump(void)`, printing every live kernel allocation grouped by call site
+/// Fails unless the kernel was built with the `alloc-trace` feature
+fn sys_allocdump() -> usize {
+    #[cfg(feature = "alloc-trace")]
+    {
+        crate::alloc_trace::dump();
+        0
+    }
+    #[cfg(not(feature = "alloc-trace"))]
+    SYSCALL_ERROR
+}
+
+/// `int setloglevel(const char *filters, size_t length)`, changing which kernel log records are printed with
+/// filters such as `debug` or `info,kalloc=debug,vm=trace`, as the `loglevel=` boot argument takes
+fn sys_setloglevel(proc: &mut Proc<'_>, arguments: [usize; 6]) -> usize {
+    let [address, length, ..] = arguments;
+    let mut buffer = [0; MAX_LOG_FILTERS_LENGTH];
+    let Some(filters) = buffer.get_mut(..length) else {
+        return SYSCALL_ERROR;
+    };
+    let copied = proc
+        .page_table()
+        .is_some_and(|page_table| page_table.copy_from_user(filters, address).is_ok());
+    let Some(filters) = copied.then(|| core::str::from_utf8(filters).ok()).flatten() else {
+        return SYSCALL_ERROR;
+    };
+    match apply_log_filters(filters) {
+        Ok(()) => {
+            log::info!("Log filters changed by {}: {}", proc.name(), filters);
+            0
+        }
+        Err(error) => {
+            log::warn!("Ignoring malformed log filters {}: {:?}", filters, error);
+            SYSCALL_ERROR
+        }
+    }
+}
+
+/// `ssize_t dmesg(char *buffer, size_t length)`, copying the oldest `length` bytes of the kernel log to `buffer`
+/// Returns how many bytes were copied
+fn sys_dmesg(proc: &mut Proc<'_>, arguments: [usize; 6]) -> usize {
+    let [address, length, ..] = arguments;
+    let (Some(page_table), Some(_)) = (proc.page_table(), address.checked_add(length)) else {
+        return SYSCALL_ERROR;
+    };
+    let mut position = 0;
+    let mut copied = 0;
+    let mut chunk = [0; 256];
+    while copied < length {
+        let chunk_length = (length - copied).min(chunk.len());
+        let read = crate::dmesg::read(&mut position, &mut chunk[..chunk_length]);
+        if read == 0 {
+            break;
+        }
+        if page_table
+            .copy_to_user(address + copied, &chunk[..read])
+            .is_err()
+        {
+            return SYSCALL_ERROR;
+        }
+        copied += read;
+    }
+    copied
+}
@@ -0,0 +1,399 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::proc::cpuid;
+use crate::vm::PAGE_SIZE;
+use crate::MAX_HART_COUNT;
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{info, warn};
+use riscv::register::satp;
+use spin::{mutex::Mutex, once::Once};
+
+/// The ASID the kernel page table uses, which is never handed out to a process
+pub(crate) const KERNEL_ASID: usize = 0;
+/// Where the ASID field starts in `satp`
+pub(crate) const SATP_ASID_SHIFT: usize = 44;
+/// The widest ASID `satp` can hold
+const SATP_ASID_BITS: usize = 16;
+/// The [`AddressSpaceId`] value of the kernel page table, which always uses [`KERNEL_ASID`]
+const KERNEL_ADDRESS_SPACE: usize = usize::MAX;
+
+/// The most pages a local flush removes one at a time, beyond which it flushes the whole address space instead
+const MAX_PAGE_FLUSHES: usize = 32;
+
+static ASID_BITS: Once<usize> = Once::new();
+
+/// Is the SBI remote fence extension available, or must the legacy calls be used?
+static HAS_REMOTE_FENCE: Once<bool> = Once::new();
+
+/// A mask of every hart that has activated a page table, and so may have TLB entries
+static STARTED_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+static ASID_ALLOCATOR: Mutex<AsidAllocator> = Mutex::new(AsidAllocator::new());
+
+/// Hands out ASIDs to address spaces. When they run out, a new generation starts, the TLB is flushed,
+/// and every address space is given a new ASID the next time it is activated. As in Linux, the address spaces the
+/// harts are running at the time keep their ASIDs instead, as the harts go on using them without activating again
+#[derive(Debug)]
+struct AsidAllocator {
+    /// The current generation, so that address spaces can tell if their ASID is still theirs
+    generation: usize,
+    /// The next ASID to hand out in this generation
+    next: usize,
+    /// The address space each hart has activated since the last rollover, if any
+    active: [Option<usize>; MAX_HART_COUNT],
+    /// The address space each hart was running at the last rollover, whose ASID is not handed out again
+    reserved: [Option<usize>; MAX_HART_COUNT],
+}
+
+impl AsidAllocator {
+    const fn new() -> Self {
+        Self {
+            generation: 1,
+            next: KERNEL_ASID + 1,
+            active: [None; MAX_HART_COUNT],
+            reserved: [None; MAX_HART_COUNT],
+        }
+    }
+
+    /// The value an address space holding `value` has once `hart` activates it, with a new ASID if its own is from
+    /// an older generation. Also returns whether a new generation started, after which every TLB must be flushed
+    /// before the new value is used
+    fn activate(&mut self, value: usize, hart: usize, asid_count: usize) -> (usize, bool) {
+        let mut rolled_over = false;
+        let value = if value >> SATP_ASID_BITS == self.generation {
+            value
+        } else if self.reserved.contains(&Some(value)) {
+            // A hart was running it at the last rollover, so it keeps its ASID in this generation
+            let renewed = self.generation << SATP_ASID_BITS | asid(value);
+            for reserved in &mut self.reserved {
+                if *reserved == Some(value) {
+                    *reserved = Some(renewed);
+                }
+            }
+            renewed
+        } else {
+            loop {
+                if self.next >= asid_count {
+                    self.roll_over();
+                    rolled_over = true;
+                }
+                let next = self.next;
+                self.next += 1;
+                if !self.is_reserved(next) {
+                    break self.generation << SATP_ASID_BITS | next;
+                }
+            }
+        };
+        self.active[hart] = Some(value);
+        (value, rolled_over)
+    }
+
+    /// Start a new generation, reserving the ASID each hart is running
+    fn roll_over(&mut self) {
+        self.generation += 1;
+        self.next = KERNEL_ASID + 1;
+        for (active, reserved) in self.active.iter_mut().zip(&mut self.reserved) {
+            // A hart that has activated nothing since the last rollover is still running what it had then
+            if let Some(value) = active.take() {
+                *reserved = Some(value);
+            }
+        }
+    }
+
+    /// Is `asid` kept for the address space a hart was running at the last rollover?
+    fn is_reserved(&self, candidate: usize) -> bool {
+        self.reserved
+            .iter()
+            .flatten()
+            .any(|&value| asid(value) == candidate)
+    }
+
+    /// Is the ASID in `value` still the address space's own in this generation?
+    fn is_current(&self, value: usize) -> bool {
+        value >> SATP_ASID_BITS == self.generation || self.reserved.contains(&Some(value))
+    }
+}
+
+/// The ASID in an address space's value, without its generation
+const fn asid(value: usize) -> usize {
+    value & ((1 << SATP_ASID_BITS) - 1)
+}
+
+/// This hart's bit in the masks of harts
+/// # Panics
+/// Panics if the hart's id is too large for a mask
+fn hart_bit(hart: usize) -> usize {
+    assert!(
+        hart < usize::BITS as usize,
+        "Hart {hart} does not fit in a mask of harts"
+    );
+    1 << hart
+}
+
+/// Find how many ASID bits this hart implements, by writing all ones to the ASID field of `satp` and reading it back
+/// Must be called with the kernel page table active, which stays active throughout
+pub(crate) fn probe_asid_bits() {
+    ASID_BITS.call_once(|| {
+        let asid_mask = ((1 << SATP_ASID_BITS) - 1) << SATP_ASID_SHIFT;
+        let satp = satp::read().bits();
+        let probed: usize;
+        unsafe {
+            asm!(
+                "csrw satp, {probe}",
+                "csrr {probed}, satp",
+                "csrw satp, {satp}",
+                probe = in(reg) satp | asid_mask,
+                probed = out(reg) probed,
+                satp = in(reg) satp,
+            );
+        }
+        // Implemented ASID bits are always the low ones
+        let asid_bits = ((probed & asid_mask) >> SATP_ASID_SHIFT).count_ones() as usize;
+        info!("Hart implements {} ASID bits", asid_bits);
+        asid_bits
+    });
+}
+
+/// The ASID of an address space, tagged with the generation it was handed out in,
+/// and the harts that may have TLB entries for it
+#[derive(Debug)]
+pub(crate) struct AddressSpaceId {
+    value: AtomicUsize,
+    /// A mask of the harts that have activated this address space since it was given its ASID
+    harts: AtomicUsize,
+}
+
+impl AddressSpaceId {
+    /// An address space that has not been given an ASID yet
+    #[allow(dead_code)]
+    pub(crate) const fn new() -> Self {
+        // Generations start at 1, so this never matches the current one
+        Self {
+            value: AtomicUsize::new(0),
+            harts: AtomicUsize::new(0),
+        }
+    }
+
+    /// The kernel's address space, which always has [`KERNEL_ASID`]
+    pub(crate) const fn kernel() -> Self {
+        Self {
+            value: AtomicUsize::new(KERNEL_ADDRESS_SPACE),
+            harts: AtomicUsize::new(0),
+        }
+    }
+
+    /// The ASID to activate this address space with on this hart,
+    /// handing out a new one if it has none in the current generation
+    pub(crate) fn activate(&self) -> usize {
+        let hart = cpuid();
+        let hart_bit = hart_bit(hart);
+        STARTED_HARTS.fetch_or(hart_bit, Ordering::AcqRel);
+        let value = self.value.load(Ordering::Acquire);
+        if value == KERNEL_ADDRESS_SPACE {
+            self.harts.fetch_or(hart_bit, Ordering::AcqRel);
+            return KERNEL_ASID;
+        }
+
+        let mut allocator = ASID_ALLOCATOR.lock();
+        let asid_count = 1 << *ASID_BITS.wait();
+        if asid_count <= KERNEL_ASID + 1 + MAX_HART_COUNT {
+            // Without an ASID to spare beyond those every hart could keep at a rollover, everything shares the
+            // kernel's, and must be flushed on every switch
+            flush_all();
+            self.value.store(
+                allocator.generation << SATP_ASID_BITS | KERNEL_ASID,
+                Ordering::Release,
+            );
+            self.harts.fetch_or(hart_bit, Ordering::AcqRel);
+            return KERNEL_ASID;
+        }
+        // Keeping its ASID, the harts that had it may still have its entries
+        let kept = allocator.is_current(value);
+        let (activated, rolled_over) = allocator.activate(value, hart, asid_count);
+        if rolled_over {
+            // Every hart may still have entries tagged with ASIDs that are about to be handed out again
+            flush_all();
+            remote_flush(
+                STARTED_HARTS.load(Ordering::Acquire) & !hart_bit,
+                0,
+                usize::MAX,
+                None,
+            );
+        }
+        self.value.store(activated, Ordering::Release);
+        if kept {
+            self.harts.fetch_or(hart_bit, Ordering::AcqRel);
+        } else {
+            self.harts.store(hart_bit, Ordering::Release);
+        }
+        asid(activated)
+    }
+
+    /// Flush `[start, start + size)` in this address space from the TLB of every hart that may have it,
+    /// returning once they all have, so that the pages that were mapped there can be freed
+    pub(crate) fn shootdown(&self, start: usize, size: usize) {
+        let Some(asid) = self.current() else {
+            return;
+        };
+        let hart_bit = hart_bit(cpuid());
+        let harts = self.harts.load(Ordering::Acquire);
+        if harts & hart_bit != 0 {
+            flush_range(start, size, asid);
+        }
+        remote_flush(harts & !hart_bit, start, size, Some(asid));
+    }
+
+    /// The ASID this address space's TLB entries are tagged with, or `None` if it can have no TLB entries,
+    /// as it has not been activated in the current generation, nor was running at the last rollover
+    pub(crate) fn current(&self) -> Option<usize> {
+        let value = self.value.load(Ordering::Acquire);
+        if value == KERNEL_ADDRESS_SPACE {
+            return Some(KERNEL_ASID);
+        }
+        ASID_ALLOCATOR
+            .lock()
+            .is_current(value)
+            .then_some(asid(value))
+    }
+}
+
+/// Flush `[start, start + size)` in the address space tagged `asid`, or in every address space if it is `None`,
+/// from the TLBs of the harts in the mask `harts`, returning once they all have.
+/// A `size` of `usize::MAX` flushes the whole address space
+/// # Panics
+/// Panics if the harts cannot be made to flush even everything, as the pages they may have entries for are about to
+/// be freed
+#[cfg(not(host))]
+fn remote_flush(harts: usize, start: usize, size: usize, asid: Option<usize>) {
+    if harts == 0 {
+        return;
+    }
+    if let Some(error) = sbi_remote_flush(harts, start, size, asid).err() {
+        warn!(
+            "Remote TLB flush of harts 0x{:x} failed with {:?}, flushing everything instead",
+            harts, error
+        );
+        if let Some(error) = sbi_remote_flush(harts, 0, usize::MAX, None).err() {
+            panic!("Remote TLB flush of harts 0x{harts:x} failed with {error:?}");
+        }
+    }
+}
+
+/// Ask the SBI to flush `[start, start + size)` from the TLBs of `harts`, as [`remote_flush`] does
+#[cfg(not(host))]
+fn sbi_remote_flush(
+    harts: usize,
+    start: usize,
+    size: usize,
+    asid: Option<usize>,
+) -> sbi_rt::SbiRet {
+    let has_remote_fence =
+        *HAS_REMOTE_FENCE.call_once(|| sbi_rt::probe_extension(sbi_rt::Fence).is_available());
+    if has_remote_fence {
+        let hart_mask = sbi_rt::HartMask::from_mask_base(harts, 0);
+        match asid {
+            Some(asid) => sbi_rt::remote_sfence_vma_asid(hart_mask, start, size, asid),
+            None => sbi_rt::remote_sfence_vma(hart_mask, start, size),
+        }
+    } else {
+        // The legacy calls take the address of the hart mask
+        let hart_mask = core::ptr::addr_of!(harts) as usize;
+        #[allow(deprecated)]
+        let error = match asid {
+            Some(asid) => sbi_rt::legacy::remote_fence_vma_asid(hart_mask, start, size, asid),
+            None => sbi_rt::legacy::remote_fence_vma(hart_mask, start, size),
+        };
+        sbi_rt::SbiRet { error, value: 0 }
+    }
+}
+
+/// Host tests have no other harts whose TLBs could need flushing
+#[cfg(host)]
+fn remote_flush(_harts: usize, _start: usize, _size: usize, _asid: Option<usize>) {}
+
+/// Flush `[start, start + size)` in the address space tagged `asid` from this hart's TLB, a page at a time for small
+/// ranges, and by flushing the whole address space for larger ones
+fn flush_range(start: usize, size: usize, asid: usize) {
+    if size / PAGE_SIZE > MAX_PAGE_FLUSHES {
+        flush_asid(asid);
+        return;
+    }
+    for virtual_address in (start..start + size).step_by(PAGE_SIZE) {
+        unsafe { riscv::asm::sfence_vma(asid, virtual_address) };
+    }
+}
+
+/// Flush every entry in this hart's TLB for the address space tagged `asid`, except global mappings
+#[cfg(not(host))]
+#[inline]
+fn flush_asid(asid: usize) {
+    unsafe { asm!("sfence.vma zero, {asid}", asid = in(reg) asid) };
+}
+
+/// Host tests never activate a page table, so there is no TLB to flush
+#[cfg(host)]
+fn flush_asid(_asid: usize) {}
+
+/// Flush every entry in this hart's TLB, in every address space
+#[inline]
+pub(crate) fn flush_all() {
+    riscv::asm::sfence_vma_all();
+}
+
+#[cfg(all(test, host))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_address_spaces_keep_their_asids_across_a_rollover() {
+        // ASIDs 1 to 3 can be handed out
+        let asid_count = 4;
+        let mut allocator = AsidAllocator::new();
+        let (first, rolled_over) = allocator.activate(0, 0, asid_count);
+        assert!(!rolled_over);
+        assert_eq!(asid(first), 1);
+        let (second, _) = allocator.activate(0, 1, asid_count);
+        let (third, _) = allocator.activate(0, 1, asid_count);
+        assert_eq!(asid(third), 3);
+
+        // Hart 0 is still running `first` and hart 1 `third`, so the new generation starts with the ASID of
+        // `second`, which no hart is running
+        let (fourth, rolled_over) = allocator.activate(0, 0, asid_count);
+        assert!(rolled_over);
+        assert_eq!(fourth, asid(second) | 2 << SATP_ASID_BITS);
+        assert!(allocator.is_current(third));
+        assert!(!allocator.is_current(second));
+        let (renewed, rolled_over) = allocator.activate(third, 1, asid_count);
+        assert!(!rolled_over);
+        assert_eq!(renewed, asid(third) | 2 << SATP_ASID_BITS);
+
+        // ASID 3 is still reserved for `third`, so `second` needs another rollover, after which only the ASIDs of
+        // `fourth` and `third` are kept
+        let (second, rolled_over) = allocator.activate(second, 1, asid_count);
+        assert!(rolled_over);
+        assert_eq!(second, 1 | 3 << SATP_ASID_BITS);
+        assert!(!allocator.is_current(first));
+        assert!(allocator.is_current(fourth));
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit in a mask of harts")]
+    fn hart_ids_past_the_mask_width_panic() {
+        hart_bit(usize::BITS as usize);
+    }
+}
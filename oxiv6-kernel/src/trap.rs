@@ -0,0 +1,68 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::backtrace::{print_frames, Frames};
+use crate::println::println;
+use crate::proc::kstack_guard_owner;
+use crate::{MAX_HART_COUNT, STACK_SIZE};
+use riscv::register::{
+    scause::{self, Exception, Trap},
+    sepc, stval,
+    stvec::{self, TrapMode},
+};
+
+/// The stacks `kernelvec` handles traps on, one per hart, so that a trap from an overflowed kernel stack has a stack
+pub(crate) static mut TRAP_STACKS: [[u8; STACK_SIZE]; MAX_HART_COUNT] =
+    [[0; STACK_SIZE]; MAX_HART_COUNT];
+
+extern "C" {
+    fn kernelvec();
+}
+
+/// Send this hart's traps in supervisor mode to `kernelvec`, which finds this hart's trap stack from `tp`
+pub(crate) fn trapinithart() {
+    unsafe { stvec::write(kernelvec as usize, TrapMode::Direct) };
+}
+
+/// Handle a trap from supervisor mode. `kernelvec` enters here on this hart's trap stack, passing the frame pointer
+/// of the interrupted code. Nothing in the kernel is expected to trap yet, so every trap prints a backtrace of the
+/// interrupted code and panics, naming the process if a kernel stack overflowed into its guard page
+#[no_mangle]
+extern "C" fn kerneltrap(frame_pointer: usize) {
+    let cause = scause::read().cause();
+    let sepc = sepc::read();
+    let stval = stval::read();
+
+    println!("kerneltrap: {cause:?} at sepc=0x{sepc:x}");
+    // The chain from the interrupted frame pointer holds the return addresses of its callers, so start at `sepc`
+    print_frames(core::iter::once(sepc).chain(Frames::from_frame_pointer(frame_pointer)));
+
+    if let Trap::Exception(
+        Exception::LoadPageFault | Exception::StorePageFault | Exception::InstructionPageFault,
+    ) = cause
+    {
+        if let Some(proc) = kstack_guard_owner(stval) {
+            panic!(
+                "kerneltrap: kernel stack overflow in process {:?} ({}), sepc=0x{:x} stval=0x{:x}",
+                proc.try_pid(),
+                proc.name(),
+                sepc,
+                stval
+            );
+        }
+    }
+    panic!("kerneltrap: unexpected {cause:?}, sepc=0x{sepc:x} stval=0x{stval:x}");
+}
@@ -1,154 +1,886 @@
-use alloc::alloc::{alloc_zeroed, Layout};
+use crate::kalloc::OutOfMemory;
+use crate::println::{print, println};
+use crate::tlb::{AddressSpaceId, SATP_ASID_SHIFT};
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
 use bitfield::{bitfield, BitMut, BitRange, BitRangeMut};
 use bitflags::bitflags;
-use core::{mem::size_of, slice::from_raw_parts_mut};
+use core::arch::asm;
+use core::cell::{Cell, UnsafeCell};
+use core::marker::PhantomData;
+use core::{fmt, mem::size_of, slice::from_raw_parts, slice::from_raw_parts_mut, str::FromStr};
+use log::{info, warn};
 use num_enum::{FromPrimitive, IntoPrimitive};
 use riscv::register::satp;
 
+/// How page tables reach physical memory: where their pages come from, and where the kernel can access a
+/// physical address. The kernel maps physical memory at the same addresses, host tests use a heap arena
+pub(crate) trait PhysicalMemory {
+    /// Allocate a zeroed page, returning its physical address
+    fn alloc_page(&self) -> Result<usize, OutOfMemory>;
+
+    /// Free the page at `physical_address`
+    /// # Safety
+    /// The page must be from [`Self::alloc_page`], and must not be used once freed
+    unsafe fn free_page(&self, physical_address: usize);
+
+    /// Where the kernel can write the page at `physical_address`
+    fn page(&self, physical_address: usize) -> *mut u8;
+
+    /// Where the kernel can read the page at `physical_address`
+    fn page_const(&self, physical_address: usize) -> *const u8 {
+        self.page(physical_address).cast_const()
+    }
+}
+
+/// Physical memory as the kernel sees it, mapped at the same addresses and allocated from the kernel heap
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct KernelMemory;
+
+impl PhysicalMemory for KernelMemory {
+    fn alloc_page(&self) -> Result<usize, OutOfMemory> {
+        let page = unsafe { alloc_zeroed(page_layout()) };
+        if page.is_null() {
+            // The table can't grow, so whatever it was growing for gives up
+            crate::proc::oom_kill();
+            Err(OutOfMemory)
+        } else {
+            Ok(page as usize)
+        }
+    }
+
+    unsafe fn free_page(&self, physical_address: usize) {
+        unsafe { dealloc(physical_address as *mut u8, page_layout()) };
+    }
+
+    fn page(&self, physical_address: usize) -> *mut u8 {
+        #[cfg(feature = "kasan")]
+        crate::kasan::check(physical_address, PAGE_SIZE, crate::kasan::Access::Write);
+        physical_address as *mut u8
+    }
+
+    fn page_const(&self, physical_address: usize) -> *const u8 {
+        #[cfg(feature = "kasan")]
+        crate::kasan::check(physical_address, PAGE_SIZE, crate::kasan::Access::Read);
+        physical_address as *const u8
+    }
+}
+
+/// How many pages a probe's page table may use: its root, and the tables below it for the kernel text
+const PROBE_PAGES: usize = 8;
+
+#[repr(C, align(4096))]
+struct ProbePages(UnsafeCell<[[u8; PAGE_SIZE]; PROBE_PAGES]>);
+
+// Only reached through `ProbeMemory`, of which there is one at a time, on the boot hart
+unsafe impl Sync for ProbePages {}
+
+static PROBE_PAGES_MEMORY: ProbePages = ProbePages(UnsafeCell::new([[0; PAGE_SIZE]; PROBE_PAGES]));
+
+/// Physical memory for the page tables that probe paging modes, which are made before there is a heap, as the
+/// paging mode decides how much memory the heap can have. Pages are handed out in turn and never reused, so only
+/// one may exist at a time
+#[derive(Debug, Default)]
+struct ProbeMemory {
+    allocated: Cell<usize>,
+}
+
+impl PhysicalMemory for ProbeMemory {
+    fn alloc_page(&self) -> Result<usize, OutOfMemory> {
+        let index = self.allocated.get();
+        if index == PROBE_PAGES {
+            return Err(OutOfMemory);
+        }
+        self.allocated.set(index + 1);
+        let page = unsafe { &mut (*PROBE_PAGES_MEMORY.0.get())[index] };
+        page.fill(0);
+        Ok(page.as_mut_ptr() as usize)
+    }
+
+    unsafe fn free_page(&self, _physical_address: usize) {}
+
+    fn page(&self, physical_address: usize) -> *mut u8 {
+        physical_address as *mut u8
+    }
+}
+
 /// A full Page Table
-#[repr(transparent)]
 #[derive(Debug)]
-pub(crate) struct PageTable<'a> {
-    first_level: &'a mut [PageTableEntry],
+pub(crate) struct PageTable<'a, M: PhysicalMemory = KernelMemory> {
+    /// The physical address of the first level table
+    root: usize,
+    mode: PagingMode,
+    asid: AddressSpaceId,
+    memory: M,
+    _tables: PhantomData<&'a mut [PageTableEntry]>,
 }
 
-impl<'a> PageTable<'a> {
-    /// Creates a new page table, located on the heap
-    pub(crate) fn new() -> Self {
-        let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE)
-            .expect("Unable to allocate for page table");
-        #[allow(clippy::cast_ptr_alignment)]
-        let page_table_page = unsafe { alloc_zeroed(layout).cast::<PageTableEntry>() };
-        PageTable {
-            first_level: unsafe {
-                from_raw_parts_mut(page_table_page, PAGE_SIZE / size_of::<PageTableEntry>())
-            },
-        }
+impl PageTable<'_> {
+    /// Creates a new page table in the paging mode chosen at boot, located on the heap
+    #[allow(dead_code)]
+    pub(crate) fn new() -> Result<Self, OutOfMemory> {
+        Self::with_memory(paging_mode(), AddressSpaceId::new(), KernelMemory)
+    }
+
+    /// Creates the kernel's page table, which always uses the kernel ASID
+    fn new_kernel() -> Result<Self, OutOfMemory> {
+        Self::with_memory(paging_mode(), AddressSpaceId::kernel(), KernelMemory)
     }
 
     /// Sets this page table as the active table
     pub(crate) fn set_as_active_table(&self) {
         unsafe {
-            satp::set(
-                satp::Mode::Sv39,
-                0,
-                (self.first_level.as_ptr() as usize) >> 12,
-            );
+            satp::set(self.mode.satp_mode(), self.asid.activate(), self.root >> 12);
         }
     }
+}
+
+impl<M: PhysicalMemory> PageTable<'_, M> {
+    /// Creates a new page table for `mode`, with its pages from `memory`
+    pub(crate) fn with_memory(
+        mode: PagingMode,
+        asid: AddressSpaceId,
+        memory: M,
+    ) -> Result<Self, OutOfMemory> {
+        Ok(PageTable {
+            root: memory.alloc_page()?,
+            mode,
+            asid,
+            memory,
+            _tables: PhantomData,
+        })
+    }
+
+    /// The `satp` value that activates this page table, giving it an ASID if it needs one
+    pub(crate) fn satp(&self) -> usize {
+        (self.mode.satp_mode() as usize) << 60
+            | self.asid.activate() << SATP_ASID_SHIFT
+            | self.root >> 12
+    }
+
+    /// The entries of the table page at `physical_address`
+    fn table(&self, physical_address: usize) -> *mut PageTableEntry {
+        #[allow(clippy::cast_ptr_alignment)]
+        self.memory.page(physical_address).cast()
+    }
+
+    /// The entries of the table page at `physical_address`, only to be read
+    fn table_const(&self, physical_address: usize) -> *const PageTableEntry {
+        #[allow(clippy::cast_ptr_alignment)]
+        self.memory.page_const(physical_address).cast()
+    }
 
     /// Map a contiguous region of virtual addresses to a contigous region of physical addresses
     /// `virtual_base` and `region_size` need not be page aligned
+    /// Refuses `permissions` that are both writable and executable, see [`Self::map_pages_writable_executable`]
     pub(crate) fn map_pages(
         &mut self,
         virtual_base: usize,
         region_size: usize,
         physical_base: usize,
         permissions: PageTableEntryFlags,
+    ) -> Result<(), PageTableMapError> {
+        if permissions.contains(PageTableEntryFlags::W | PageTableEntryFlags::X) {
+            return Err(PageTableMapError::WritableAndExecutable);
+        }
+        self.map_pages_writable_executable(virtual_base, region_size, physical_base, permissions)
+    }
+
+    /// Map a contiguous region like [`Self::map_pages`], but allow the mapping to be both writable and executable
+    /// Megapages and gigapages are used wherever the alignment and size of the region permit
+    #[allow(dead_code)]
+    pub(crate) fn map_pages_writable_executable(
+        &mut self,
+        virtual_base: usize,
+        region_size: usize,
+        physical_base: usize,
+        permissions: PageTableEntryFlags,
     ) -> Result<(), PageTableMapError> {
         assert!(region_size != 0, "map_pages: size");
 
         let virtual_page_start = PGROUNDDOWN!(virtual_base);
         let virtual_page_end = PGROUNDDOWN!(virtual_base + region_size - 1);
-        for virtual_addr in (virtual_page_start..virtual_page_end).step_by(PAGE_SIZE) {
-            self.walk_mut(virtual_addr, true, |pte| {
-                assert!(!pte.valid(), "map_pages: remap");
-                pte.set_mapping(virtual_addr - virtual_page_start + physical_base);
+        let mut virtual_addr = virtual_page_start;
+        while virtual_addr <= virtual_page_end {
+            let physical_addr = virtual_addr - virtual_page_start + physical_base;
+            let mut level = (0..self.mode.levels())
+                .rev()
+                .find(|&level| {
+                    physical_addr.is_multiple_of(level_size(level))
+                        && region_covers(virtual_addr, virtual_page_end, level)
+                })
+                .unwrap_or(0);
+            // A table under the entry means part of its range is already mapped, so use smaller pages instead
+            while !self.walk_mut(virtual_addr, level, true, |pte, pte_level| {
+                assert!(pte_level == level && !pte.is_leaf(), "map_pages: remap");
+                if pte.valid() {
+                    return false;
+                }
+                pte.set_mapping(physical_addr);
                 pte.set_flags(permissions | PageTableEntryFlags::V);
+                true
+            })? {
+                level -= 1;
+            }
+            virtual_addr += level_size(level);
+        }
+        Ok(())
+    }
+
+    /// Unmap every valid leaf in a region of virtual addresses
+    /// `on_unmap` is called with the virtual address and the old entry of each page once it is cleared and flushed
+    /// from every hart's TLB, so that the caller can write back or free the physical page.
+    /// Pages that were never mapped are skipped.
+    /// A superpage inside the region is passed to `on_unmap` as one entry, one only partly inside is split first.
+    pub(crate) fn unmap_pages(
+        &mut self,
+        virtual_base: usize,
+        region_size: usize,
+        mut on_unmap: impl FnMut(usize, PageTableEntry),
+    ) -> Result<(), PageTableWalkError> {
+        assert!(region_size != 0, "unmap_pages: size");
+
+        // Only clear the valid bit of each leaf at first, which the MMU is the only reader of, so that the rest of
+        // the entry is still there for `on_unmap` once the whole range has been flushed with one shootdown
+        let virtual_page_start = PGROUNDDOWN!(virtual_base);
+        let virtual_page_end = PGROUNDDOWN!(virtual_base + region_size - 1);
+        let mut cleared: Option<(usize, usize)> = None;
+        let mut result = Ok(());
+        let mut virtual_addr = virtual_page_start;
+        while virtual_addr <= virtual_page_end {
+            let walked = self.walk(
+                virtual_addr,
+                0,
+                false,
+                |pte, level| -> Result<_, PageTableWalkError> {
+                    if !pte.is_leaf() {
+                        // A missing table means there is nothing to unmap in the rest of its range
+                        return Ok((next_entry_address(virtual_addr, level), false));
+                    }
+                    if !region_covers(virtual_addr, virtual_page_end, level) {
+                        split_superpage(&self.memory, pte, level)?;
+                        return Ok((virtual_addr, false));
+                    }
+                    pte.set_valid(false);
+                    Ok((next_entry_address(virtual_addr, level), true))
+                },
+            );
+            let (next_addr, was_cleared) = match walked.and_then(|walked| walked) {
+                Ok(walked) => walked,
+                Err(error) => {
+                    // The leaves cleared so far must still be flushed and handed over
+                    result = Err(error);
+                    break;
+                }
+            };
+            if was_cleared {
+                let start = cleared.map_or(virtual_addr, |(start, _)| start);
+                cleared = Some((start, next_addr));
+            }
+            virtual_addr = next_addr;
+        }
+        let Some((start, end)) = cleared else {
+            return result;
+        };
+        self.asid.shootdown(start, end - start);
+
+        // The cleared leaves are the only invalid entries in the range that are not zero
+        let mut virtual_addr = start;
+        while virtual_addr < end {
+            let (next_addr, unmapped) = self.walk(virtual_addr, 0, false, |pte, level| {
+                let next_addr = next_entry_address(virtual_addr, level);
+                if pte.valid() || pte.0 == 0 {
+                    return (next_addr, None);
+                }
+                let mut unmapped = *pte;
+                unmapped.set_valid(true);
+                *pte = PageTableEntry(0);
+                (next_addr, Some(unmapped))
             })?;
+            if let Some(pte) = unmapped {
+                on_unmap(virtual_addr, pte);
+            }
+            virtual_addr = next_addr;
+        }
+        result
+    }
+
+    /// Change the permissions of every valid leaf in a region of virtual addresses, keeping the accessed and dirty bits
+    /// Pages that were never mapped are skipped. Refuses `permissions` that are both writable and executable.
+    /// A superpage only partly inside the region is split first.
+    pub(crate) fn protect_pages(
+        &mut self,
+        virtual_base: usize,
+        region_size: usize,
+        permissions: PageTableEntryFlags,
+    ) -> Result<(), PageTableMapError> {
+        assert!(region_size != 0, "protect_pages: size");
+        // A valid entry without R, W or X points to the next level of the table instead of a page
+        assert!(
+            permissions.intersects(PageTableEntryFlags::RX),
+            "protect_pages: not a leaf"
+        );
+        if permissions.contains(PageTableEntryFlags::W | PageTableEntryFlags::X) {
+            return Err(PageTableMapError::WritableAndExecutable);
+        }
+
+        // Stale entries with fewer permissions only cause a spurious fault, so only the downgraded range is flushed
+        let virtual_page_start = PGROUNDDOWN!(virtual_base);
+        let virtual_page_end = PGROUNDDOWN!(virtual_base + region_size - 1);
+        let mut downgraded: Option<(usize, usize)> = None;
+        let mut result = Ok(());
+        let mut virtual_addr = virtual_page_start;
+        while virtual_addr <= virtual_page_end {
+            let walked = self.walk(
+                virtual_addr,
+                0,
+                false,
+                |pte, level| -> Result<_, PageTableWalkError> {
+                    if !pte.is_leaf() {
+                        // A missing table means there is nothing to change in the rest of its range
+                        return Ok((next_entry_address(virtual_addr, level), false));
+                    }
+                    if !region_covers(virtual_addr, virtual_page_end, level) {
+                        split_superpage(&self.memory, pte, level)?;
+                        return Ok((virtual_addr, false));
+                    }
+                    let old_flags = pte.get_flags();
+                    let kept = old_flags & (PageTableEntryFlags::A | PageTableEntryFlags::D);
+                    pte.set_flags(permissions | kept | PageTableEntryFlags::V);
+                    let removed = old_flags.difference(permissions);
+                    let downgraded = removed.intersects(
+                        PageTableEntryFlags::RW | PageTableEntryFlags::X | PageTableEntryFlags::U,
+                    );
+                    Ok((next_entry_address(virtual_addr, level), downgraded))
+                },
+            );
+            let (next_addr, was_downgraded) = match walked.and_then(|walked| walked) {
+                Ok(walked) => walked,
+                Err(error) => {
+                    // The pages downgraded so far must still be flushed
+                    result = Err(error.into());
+                    break;
+                }
+            };
+            if was_downgraded {
+                let start = downgraded.map_or(virtual_addr, |(start, _)| start);
+                downgraded = Some((start, next_addr));
+            }
+            virtual_addr = next_addr;
+        }
+        if let Some((start, end)) = downgraded {
+            self.asid.shootdown(start, end - start);
+        }
+        result
+    }
+
+    /// Translate a virtual address to the physical address it is mapped to, if any
+    #[allow(dead_code)]
+    pub(crate) fn translate(&self, virtual_address: usize) -> Option<usize> {
+        self.walk_const(virtual_address, |pte, level| {
+            pte.valid()
+                .then(|| pte.physical_address() + (virtual_address % level_size(level)))
+        })
+        .ok()
+        .flatten()
+    }
+
+    /// Copy `destination.len()` bytes from user memory at `virtual_address`
+    /// Fails if any of the bytes are not mapped readable for user mode
+    pub(crate) fn copy_from_user(
+        &self,
+        destination: &mut [u8],
+        virtual_address: usize,
+    ) -> Result<(), BadUserAddress> {
+        self.user_pages(
+            virtual_address,
+            destination.len(),
+            false,
+            |page, copied, length| unsafe {
+                page.copy_to_nonoverlapping(destination[copied..].as_mut_ptr(), length);
+            },
+        )
+    }
+
+    /// Copy `source` to user memory at `virtual_address`
+    /// Fails if any of the bytes are not mapped writable for user mode, and may have copied some of them
+    pub(crate) fn copy_to_user(
+        &self,
+        virtual_address: usize,
+        source: &[u8],
+    ) -> Result<(), BadUserAddress> {
+        self.user_pages(
+            virtual_address,
+            source.len(),
+            true,
+            |page, copied, length| unsafe {
+                page.copy_from_nonoverlapping(source[copied..].as_ptr(), length);
+            },
+        )
+    }
+
+    /// Run `copy` on where the kernel can access each piece of the `length` bytes of user memory at
+    /// `virtual_address` that lies in one page, along with how far into the bytes the piece is, and its length
+    /// The kernel reaches the pages around the MMU, so pages to be written are marked accessed and dirty here, as a
+    /// user store would mark them, or shared file mappings would not write them back
+    fn user_pages(
+        &self,
+        virtual_address: usize,
+        length: usize,
+        writable: bool,
+        mut copy: impl FnMut(*mut u8, usize, usize),
+    ) -> Result<(), BadUserAddress> {
+        let end = virtual_address
+            .checked_add(length)
+            .filter(|&end| end <= self.mode.max_virtual_address())
+            .ok_or(BadUserAddress)?;
+        let mut address = virtual_address;
+        while address < end {
+            let piece = (PGROUNDDOWN!(address) + PAGE_SIZE).min(end) - address;
+            let translate = |pte: &PageTableEntry, level| {
+                let allowed = if writable {
+                    pte.writeable()
+                } else {
+                    pte.readable()
+                };
+                (pte.valid() && allowed && pte.user_accessible())
+                    .then(|| pte.physical_address() + (address % level_size(level)))
+            };
+            let physical_address = if writable {
+                self.walk(address, 0, false, |pte, level| {
+                    let physical_address = translate(pte, level)?;
+                    pte.set_flags(
+                        pte.get_flags() | PageTableEntryFlags::A | PageTableEntryFlags::D,
+                    );
+                    Some(physical_address)
+                })
+            } else {
+                self.walk_const(address, translate)
+            }
+            .ok()
+            .flatten()
+            .ok_or(BadUserAddress)?;
+            let page = if writable {
+                self.memory.page(PGROUNDDOWN!(physical_address))
+            } else {
+                // `copy` only reads the page
+                self.memory
+                    .page_const(PGROUNDDOWN!(physical_address))
+                    .cast_mut()
+            };
+            let page = page.wrapping_add(physical_address % PAGE_SIZE);
+            copy(page, address - virtual_address, piece);
+            address += piece;
         }
         Ok(())
     }
 
-    pub(crate) fn walk_mut(
+    /// Walk to the entry for `virtual_address` at `leaf_level`, and run `pte_edit` on it and its level
+    /// Level 0 entries map 4 KiB pages, level 1 entries 2 MiB megapages and level 2 entries 1 GiB gigapages.
+    /// The walk stops early at a superpage, or at a missing table if `should_allocate` is not set.
+    pub(crate) fn walk_mut<T>(
         &mut self,
         virtual_address: usize,
+        leaf_level: usize,
         should_allocate: bool,
-        pte_edit: impl FnOnce(&mut PageTableEntry),
-    ) -> Result<(), PageTableWalkError> {
-        assert!(virtual_address < MAX_VIRTUAL_ADDRESS, "walk_mut");
+        pte_edit: impl FnOnce(&mut PageTableEntry, usize) -> T,
+    ) -> Result<T, PageTableWalkError> {
+        self.walk(virtual_address, leaf_level, should_allocate, pte_edit)
+    }
 
-        let mut page_table = core::ptr::addr_of_mut!(self.first_level[0]);
+    /// [`Self::walk_mut`] for callers whose `pte_edit` needs to borrow the table too
+    /// The table pages are only reached through physical addresses, so `&self` is enough to edit them
+    fn walk<T>(
+        &self,
+        virtual_address: usize,
+        leaf_level: usize,
+        should_allocate: bool,
+        pte_edit: impl FnOnce(&mut PageTableEntry, usize) -> T,
+    ) -> Result<T, PageTableWalkError> {
+        assert!(
+            virtual_address < self.mode.max_virtual_address(),
+            "walk_mut"
+        );
+
+        let mut page_table = self.table(self.root);
 
-        for level in (1..=2).rev() {
-            let page_index = (virtual_address >> (12 + (9 * level))) & 0x1FF;
+        for level in (leaf_level + 1..self.mode.levels()).rev() {
+            let page_index = page_index(virtual_address, level);
             let page_table_entry = unsafe { page_table.wrapping_add(page_index).as_mut() }.unwrap();
-            if page_table_entry.valid() {
-                page_table =
-                    core::ptr::addr_of_mut!(page_table_entry.pa_mut::<PageTableEntry>()[0]);
-            } else if !should_allocate {
-                return Err(PageTableWalkError::PageTableUnallocated);
+            if page_table_entry.is_leaf() || (!page_table_entry.valid() && !should_allocate) {
+                return Ok(pte_edit(page_table_entry, level));
+            } else if page_table_entry.valid() {
+                page_table = self.table(page_table_entry.physical_address());
             } else {
-                let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE)
-                    .expect("Unable to allocate for page table");
-                page_table = unsafe {
-                    #[allow(clippy::cast_ptr_alignment)]
-                    alloc_zeroed(layout).cast::<PageTableEntry>()
-                };
-                if page_table.is_null() {
-                    return Err(PageTableWalkError::UnableToAllocate);
-                }
-                page_table_entry.set_mapping(page_table as usize);
+                let table_address = self.memory.alloc_page()?;
+                page_table_entry.set_mapping(table_address);
                 page_table_entry.set_valid(true);
+                page_table = self.table(table_address);
             }
         }
 
-        pte_edit(
+        Ok(pte_edit(
             unsafe {
                 page_table
-                    .wrapping_add((virtual_address >> 12) & 0x1FF)
+                    .wrapping_add(page_index(virtual_address, leaf_level))
                     .as_mut()
             }
             .unwrap(),
-        );
-        Ok(())
+            leaf_level,
+        ))
     }
 
-    #[allow(dead_code)]
+    /// Find the leaf entry for `virtual_address` at whatever level it is, and run `pte_lookup` on it and its level
+    /// If nothing is mapped, `pte_lookup` may be given an invalid level 0 entry
     pub(crate) fn walk_const<T>(
         &self,
         virtual_address: usize,
-        pte_lookup: impl FnOnce(&PageTableEntry) -> T,
+        pte_lookup: impl FnOnce(&PageTableEntry, usize) -> T,
     ) -> Result<T, PageTableWalkError> {
-        assert!(virtual_address < MAX_VIRTUAL_ADDRESS, "walk");
+        assert!(virtual_address < self.mode.max_virtual_address(), "walk");
 
-        let mut page_table = core::ptr::addr_of!(self.first_level[0]);
+        let mut page_table = self.table_const(self.root);
 
-        for level in (1..=2).rev() {
-            let page_index = (virtual_address >> (12 + (9 * level))) & 0x1FF;
+        for level in (1..self.mode.levels()).rev() {
+            let page_index = page_index(virtual_address, level);
             let page_table_entry = unsafe { page_table.wrapping_add(page_index).as_ref() }.unwrap();
-            if page_table_entry.valid() {
-                page_table = core::ptr::addr_of!(page_table_entry.pa_const::<PageTableEntry>()[0]);
+            if page_table_entry.is_leaf() {
+                return Ok(pte_lookup(page_table_entry, level));
+            } else if page_table_entry.valid() {
+                page_table = self.table_const(page_table_entry.physical_address());
             } else {
                 return Err(PageTableWalkError::PageTableUnallocated);
             }
         }
-        return Ok(pte_lookup(
+        Ok(pte_lookup(
             unsafe {
                 page_table
-                    .wrapping_add((virtual_address >> 12) & 0x1FF)
+                    .wrapping_add(page_index(virtual_address, 0))
                     .as_ref()
             }
             .unwrap(),
-        ));
+            0,
+        ))
+    }
+
+    /// Print every valid entry in this page table as a tree, one table per indent
+    /// Runs of leaves that map contiguous memory with identical flags are printed as a single range
+    pub(crate) fn dump(&self) {
+        println!("page table 0x{:x} ({:?})", self.root, self.mode);
+        dump_table(&self.memory, self.root, self.mode.levels() - 1, 0, 1);
+    }
+}
+
+/// Print the valid entries of the table at `table_address` and `level`, which maps from `base_address`,
+/// indented by `depth`
+fn dump_table(
+    memory: &impl PhysicalMemory,
+    table_address: usize,
+    level: usize,
+    base_address: usize,
+    depth: usize,
+) {
+    let page_table = table_entries(memory, table_address);
+    let mut index = 0;
+    while index < page_table.len() {
+        let pte = page_table[index];
+        if !pte.valid() {
+            index += 1;
+            continue;
+        }
+        let virtual_address = base_address + index * level_size(level);
+        for _ in 0..depth {
+            print!(" ..");
+        }
+        if pte.is_leaf() {
+            // Extend the run while the next leaf carries on where this one stops
+            let mut last = index;
+            while page_table.get(last + 1).is_some_and(|next| {
+                next.is_leaf()
+                    && next.get_flags().bits() == pte.get_flags().bits()
+                    && next.rsw() == pte.rsw()
+                    && next.pa_int() == page_table[last].pa_int() + level_size(level) as u64
+            }) {
+                last += 1;
+            }
+            let length = (last - index + 1) * level_size(level);
+            println!(
+                "{}-{}: va 0x{:x}-0x{:x} pa 0x{:x}-0x{:x} {} {:?}",
+                index,
+                last,
+                virtual_address,
+                virtual_address + length,
+                pte.pa_int(),
+                pte.pa_int() + length as u64,
+                pte.get_flags(),
+                pte.rsw()
+            );
+            index = last + 1;
+        } else {
+            println!("{}: table pa 0x{:x}", index, pte.pa_int());
+            dump_table(
+                memory,
+                pte.physical_address(),
+                level - 1,
+                virtual_address,
+                depth + 1,
+            );
+            index += 1;
+        }
+    }
+}
+
+impl<M: PhysicalMemory> Drop for PageTable<'_, M> {
+    /// Free the page table pages, but not the memory they map, which belongs to whoever mapped it
+    fn drop(&mut self) {
+        unsafe { free_table(&self.memory, self.root, self.mode.levels() - 1) };
+    }
+}
+
+/// The entries of the table page at `table_address`
+fn table_entries(memory: &impl PhysicalMemory, table_address: usize) -> &[PageTableEntry] {
+    #[allow(clippy::cast_ptr_alignment)]
+    unsafe {
+        from_raw_parts(
+            memory.page_const(table_address).cast(),
+            PAGE_SIZE / size_of::<PageTableEntry>(),
+        )
+    }
+}
+
+/// Free the table page at `table_address` and `level`, after the tables below it
+/// # Safety
+/// The page must be a page table page from [`PageTable`], and must not be used once freed
+unsafe fn free_table(memory: &impl PhysicalMemory, table_address: usize, level: usize) {
+    if level > 0 {
+        for pte in table_entries(memory, table_address) {
+            if pte.valid() && !pte.is_leaf() {
+                unsafe { free_table(memory, pte.physical_address(), level - 1) };
+            }
+        }
+    }
+    unsafe { memory.free_page(table_address) };
+}
+
+/// The virtual memory schemes oxiv6 can run with, which differ in how many levels their page tables have
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub(crate) enum PagingMode {
+    Sv39,
+    Sv48,
+    Sv57,
+}
+
+impl PagingMode {
+    /// The number of levels in a page table
+    pub(crate) const fn levels(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+            PagingMode::Sv57 => 5,
+        }
+    }
+
+    /// One beyond the highest possible virtual address.
+    /// This is actually one bit less than the max allowed by the mode, to avoid having to sign-extend
+    /// virtual addresses that have the high bit set.
+    pub(crate) const fn max_virtual_address(self) -> usize {
+        1 << (9 * self.levels() + 12 - 1)
+    }
+
+    fn satp_mode(self) -> satp::Mode {
+        match self {
+            PagingMode::Sv39 => satp::Mode::Sv39,
+            PagingMode::Sv48 => satp::Mode::Sv48,
+            PagingMode::Sv57 => satp::Mode::Sv57,
+        }
+    }
+
+    /// Can this hart use this mode? Checked by briefly switching to a page table that maps only the kernel text,
+    /// as `satp` ignores writes of unsupported modes.
+    fn is_supported(self) -> bool {
+        // The kernel ASID keeps the probe away from the ASID allocator, which is set up later
+        let mut page_table =
+            PageTable::with_memory(self, AddressSpaceId::kernel(), ProbeMemory::default())
+                .expect("Unable to allocate a page table to probe paging modes");
+        page_table
+            .map_pages(
+                crate::_start as usize,
+                crate::etext as usize - crate::_start as usize,
+                crate::_start as usize,
+                PageTableEntryFlags::RX,
+            )
+            .expect("Unable to map kernel text to probe paging modes");
+        let satp = page_table.satp();
+        let read_back: usize;
+        // Nothing here may touch the stack or data, which are unmapped until satp is cleared again
+        unsafe {
+            asm!(
+                "csrw satp, {satp}",
+                "csrrw {read_back}, satp, zero",
+                "sfence.vma zero, zero",
+                satp = in(reg) satp,
+                read_back = lateout(reg) read_back,
+            );
+        }
+        read_back == satp
+    }
+}
+
+impl FromStr for PagingMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "sv39" => Ok(PagingMode::Sv39),
+            "sv48" => Ok(PagingMode::Sv48),
+            "sv57" => Ok(PagingMode::Sv57),
+            _ => Err(()),
+        }
+    }
+}
+
+static PAGING_MODE: spin::once::Once<PagingMode> = spin::once::Once::new();
+
+/// Choose the paging mode for every page table. This is the largest mode the hart supports,
+/// unless the `paging=` boot argument or an `sv39`, `sv48` or `sv57` feature asks for a supported one.
+/// Must run on the boot hart with paging off, before any [`PageTable`] is made. Needs no heap, as the paging mode
+/// decides how much physical memory the kernel can map.
+pub(crate) fn select_paging_mode() {
+    PAGING_MODE.call_once(|| {
+        let requested =
+            crate::dev::spec::get_boot_arguments()
+                .paging
+                .or(if cfg!(feature = "sv39") {
+                    Some(PagingMode::Sv39)
+                } else if cfg!(feature = "sv48") {
+                    Some(PagingMode::Sv48)
+                } else if cfg!(feature = "sv57") {
+                    Some(PagingMode::Sv57)
+                } else {
+                    None
+                });
+        // Sv39 is the smallest mode oxiv6 supports, so it is assumed rather than probed
+        let largest = [PagingMode::Sv57, PagingMode::Sv48]
+            .into_iter()
+            .find(|mode| mode.is_supported())
+            .unwrap_or(PagingMode::Sv39);
+        let mode = match requested {
+            Some(mode) if mode == PagingMode::Sv39 || mode.is_supported() => mode,
+            Some(mode) => {
+                warn!(
+                    "Paging mode {:?} is not supported, using {:?}",
+                    mode, largest
+                );
+                largest
+            }
+            None => largest,
+        };
+        info!("Using paging mode {:?}", mode);
+        mode
+    });
+}
+
+/// The paging mode chosen by [`select_paging_mode`]
+#[inline]
+pub(crate) fn paging_mode() -> PagingMode {
+    *PAGING_MODE.wait()
+}
+
+/// The address of the trampoline page, the highest page in every address space
+#[inline]
+pub(crate) fn trampoline_address() -> usize {
+    paging_mode().max_virtual_address() - PAGE_SIZE
+}
+
+/// The number of bytes mapped by a leaf entry at `level`
+#[inline]
+const fn level_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+/// The index of the entry for `virtual_address` in its page table at `level`
+#[inline]
+const fn page_index(virtual_address: usize, level: usize) -> usize {
+    (virtual_address >> (12 + (9 * level))) & 0x1FF
+}
+
+/// The start of the range mapped by the entry after the one for `virtual_address` at `level`
+#[inline]
+const fn next_entry_address(virtual_address: usize, level: usize) -> usize {
+    (virtual_address & !(level_size(level) - 1)) + level_size(level)
+}
+
+/// Does the region from `virtual_address` to the page at `virtual_page_end` cover a whole leaf at `level`,
+/// starting at `virtual_address`?
+#[inline]
+const fn region_covers(virtual_address: usize, virtual_page_end: usize, level: usize) -> bool {
+    virtual_address.is_multiple_of(level_size(level))
+        && virtual_page_end - virtual_address >= level_size(level) - PAGE_SIZE
+}
+
+/// The layout of a page table page
+const fn page_layout() -> Layout {
+    unsafe { Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE) }
+}
+
+/// Replace the superpage `pte` at `level` with a table of leaves one level down,
+/// mapping the same memory with the same flags
+fn split_superpage(
+    memory: &impl PhysicalMemory,
+    pte: &mut PageTableEntry,
+    level: usize,
+) -> Result<(), PageTableWalkError> {
+    let table_address = memory.alloc_page()?;
+    let physical_base = pte.physical_address();
+    #[allow(clippy::cast_ptr_alignment)]
+    let entries = unsafe {
+        from_raw_parts_mut(
+            memory.page(table_address).cast::<PageTableEntry>(),
+            PAGE_SIZE / size_of::<PageTableEntry>(),
+        )
+    };
+    for (index, entry) in entries.iter_mut().enumerate() {
+        *entry = *pte;
+        entry.set_mapping(physical_base + index * level_size(level - 1));
     }
+    *pte = PageTableEntry(0);
+    pte.set_mapping(table_address);
+    pte.set_valid(true);
+    Ok(())
 }
 
+/// User memory was not mapped for the kernel to read or write it as a system call asked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BadUserAddress;
+
 #[derive(Debug)]
 pub(crate) enum PageTableWalkError {
     PageTableUnallocated,
-    UnableToAllocate,
+    /// A page table page was needed, but memory ran out
+    OutOfMemory,
 }
 
 #[derive(Debug)]
 pub(crate) enum PageTableMapError {
     #[allow(dead_code)]
     PageTableWalkError(PageTableWalkError),
+    /// The mapping would be both writable and executable, without opting in to that
+    WritableAndExecutable,
+    /// A page table page was needed, but memory ran out
+    OutOfMemory,
+}
+
+impl From<OutOfMemory> for PageTableWalkError {
+    fn from(_: OutOfMemory) -> Self {
+        Self::OutOfMemory
+    }
 }
 
 impl From<PageTableWalkError> for PageTableMapError {
     fn from(value: PageTableWalkError) -> Self {
-        Self::PageTableWalkError(value)
+        match value {
+            PageTableWalkError::OutOfMemory => Self::OutOfMemory,
+            value @ PageTableWalkError::PageTableUnallocated => Self::PageTableWalkError(value),
+        }
     }
 }
 
@@ -156,7 +888,8 @@ pub(crate) static KERNEL_PAGE_TABLE: spin::once::Once<PageTable<'static>> = spin
 
 pub(crate) fn kvmmake() {
     KERNEL_PAGE_TABLE.call_once(|| {
-        let mut page_table = PageTable::new();
+        let mut page_table =
+            PageTable::new_kernel().expect("Unable to allocate the kernel page table");
 
         page_table
             .map_pages(
@@ -169,19 +902,40 @@ pub(crate) fn kvmmake() {
         page_table
             .map_pages(
                 crate::etext as usize,
-                crate::dev::spec::get_physical_memory_size() - crate::etext as usize,
+                crate::erodata as usize - crate::etext as usize,
                 crate::etext as usize,
-                PageTableEntryFlags::RW,
+                PageTableEntryFlags::R,
             )
-            .expect("Unable to map data");
+            .expect("Unable to map read only data");
+        // Map all of RAM around the kernel text and read only data, which may be spread over several regions
+        for region in crate::dev::spec::get_memory_regions() {
+            for (start, end) in [
+                (
+                    region.start,
+                    core::cmp::min(region.end, crate::_start as usize),
+                ),
+                (
+                    core::cmp::max(region.start, crate::erodata as usize),
+                    region.end,
+                ),
+            ] {
+                let (start, end) = (PGROUNDUP!(start), PGROUNDDOWN!(end));
+                if start < end {
+                    page_table
+                        .map_pages(start, end - start, start, PageTableEntryFlags::RW)
+                        .expect("Unable to map data");
+                }
+            }
+        }
         page_table
             .map_pages(
-                TRAMPOLINE,
+                trampoline_address(),
                 PAGE_SIZE,
                 crate::trampoline as usize,
                 PageTableEntryFlags::RX,
             )
             .expect("Unable to map trampoline page");
+        crate::proc::proc_mapstacks(&mut page_table);
 
         page_table
     });
@@ -231,6 +985,24 @@ bitflags! {
     }
 }
 
+impl fmt::Display for PageTableEntryFlags {
+    /// Show the flags in `vrwxuad` order, with `-` for any that are clear
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (flag, letter) in [
+            (Self::V, 'v'),
+            (Self::R, 'r'),
+            (Self::W, 'w'),
+            (Self::X, 'x'),
+            (Self::U, 'u'),
+            (Self::A, 'a'),
+            (Self::D, 'd'),
+        ] {
+            fmt::Write::write_char(f, if self.contains(flag) { letter } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
 impl PageTableEntry {
     /// Clear the accessed bit on the Page Table Entry
     /// Cannot set this bit, only read and clear
@@ -251,6 +1023,13 @@ impl PageTableEntry {
         self.pa() << 12
     }
 
+    /// The physical address this PTE points to
+    #[must_use]
+    #[allow(clippy::trivially_copy_pass_by_ref, clippy::missing_panics_doc)]
+    pub fn physical_address(&self) -> usize {
+        usize::try_from(self.pa_int()).unwrap()
+    }
+
     /// Map this PTE to a physical address as a mutable slice
     #[must_use]
     #[allow(clippy::mut_from_ref)]
@@ -280,6 +1059,13 @@ impl PageTableEntry {
         self.set_pa(u64::try_from(physical_address).unwrap() >> 12);
     }
 
+    /// Does this PTE map memory, rather than point to the next level of the page table?
+    #[must_use]
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub fn is_leaf(&self) -> bool {
+        self.valid() && (self.readable() || self.writeable() || self.executable())
+    }
+
     /// Get the flag bits in this PTE
     #[must_use]
     #[allow(clippy::trivially_copy_pass_by_ref)]
@@ -318,12 +1104,6 @@ pub enum RSW {
 
 /// The size of pages used in oxiv6
 pub(crate) const PAGE_SIZE: usize = 4096;
-/// One beyond the highest possible virtual address.
-/// `MAX_VIRTUAL_ADDRESS` is actually one bit less than the max allowed by
-/// Sv39, to avoid having to sign-extend virtual addresses
-/// that have the high bit set.
-pub(crate) const MAX_VIRTUAL_ADDRESS: usize = 1 << (9 + 9 + 9 + 12 - 1);
-pub(crate) const TRAMPOLINE: usize = MAX_VIRTUAL_ADDRESS - PAGE_SIZE;
 
 macro_rules! PGROUNDUP {
     ($e:expr) => {
@@ -340,3 +1120,453 @@ macro_rules! PGROUNDDOWN {
 
 pub(crate) use PGROUNDDOWN;
 pub(crate) use PGROUNDUP;
+
+#[cfg(all(test, host))]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use std::alloc::{GlobalAlloc, System};
+    use std::vec::Vec;
+
+    /// Where the arena's pages appear in physical memory
+    const ARENA_BASE: usize = 0x8000_0000;
+    const ARENA_PAGES: usize = 32;
+
+    /// Page table pages from a buffer on the host heap, standing in for physical memory at `ARENA_BASE`
+    struct Arena {
+        pages: *mut u8,
+        in_use: RefCell<Vec<bool>>,
+    }
+
+    impl Arena {
+        fn new() -> Self {
+            let layout = Layout::from_size_align(ARENA_PAGES * PAGE_SIZE, PAGE_SIZE).unwrap();
+            Self {
+                pages: unsafe { System.alloc(layout) },
+                in_use: RefCell::new(std::vec![false; ARENA_PAGES]),
+            }
+        }
+
+        fn pages_in_use(&self) -> usize {
+            self.in_use
+                .borrow()
+                .iter()
+                .filter(|&&in_use| in_use)
+                .count()
+        }
+
+        fn index(physical_address: usize) -> usize {
+            assert!(physical_address.is_multiple_of(PAGE_SIZE), "not a page");
+            let index = (physical_address - ARENA_BASE) / PAGE_SIZE;
+            assert!(index < ARENA_PAGES, "outside the arena");
+            index
+        }
+    }
+
+    impl Drop for Arena {
+        fn drop(&mut self) {
+            let layout = Layout::from_size_align(ARENA_PAGES * PAGE_SIZE, PAGE_SIZE).unwrap();
+            unsafe { System.dealloc(self.pages, layout) };
+        }
+    }
+
+    impl PhysicalMemory for &Arena {
+        fn alloc_page(&self) -> Result<usize, OutOfMemory> {
+            let mut in_use = self.in_use.borrow_mut();
+            let index = in_use
+                .iter()
+                .position(|&in_use| !in_use)
+                .ok_or(OutOfMemory)?;
+            in_use[index] = true;
+            unsafe { self.pages.add(index * PAGE_SIZE).write_bytes(0, PAGE_SIZE) };
+            Ok(ARENA_BASE + index * PAGE_SIZE)
+        }
+
+        unsafe fn free_page(&self, physical_address: usize) {
+            let index = Arena::index(physical_address);
+            let mut in_use = self.in_use.borrow_mut();
+            assert!(in_use[index], "double free of a page table page");
+            in_use[index] = false;
+        }
+
+        fn page(&self, physical_address: usize) -> *mut u8 {
+            let index = Arena::index(physical_address);
+            assert!(self.in_use.borrow()[index], "use of a free page table page");
+            unsafe { self.pages.add(index * PAGE_SIZE) }
+        }
+    }
+
+    fn page_table(arena: &Arena) -> PageTable<'_, &Arena> {
+        PageTable::with_memory(PagingMode::Sv39, AddressSpaceId::new(), arena).unwrap()
+    }
+
+    /// The level of the leaf mapping `virtual_address`
+    fn leaf_level(page_table: &PageTable<'_, &Arena>, virtual_address: usize) -> usize {
+        page_table
+            .walk_const(virtual_address, |pte, level| {
+                assert!(pte.is_leaf());
+                level
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn entry_fields_and_flags() {
+        let mut pte = PageTableEntry::from(0);
+        pte.set_mapping(0x8020_3000);
+        pte.set_flags(
+            PageTableEntryFlags::V
+                | PageTableEntryFlags::RW
+                | PageTableEntryFlags::A
+                | PageTableEntryFlags::D,
+        );
+        pte.set_rsw(RSW::COWPage);
+        assert_eq!(u64::from(pte), (0x8020_3000 >> 12 << 10) | (1 << 8) | 0xc7);
+        assert_eq!(pte.pa_int(), 0x8020_3000);
+        assert_eq!(pte.physical_address(), 0x8020_3000);
+        assert!(pte.valid() && pte.readable() && pte.writeable() && !pte.executable());
+        assert!(pte.is_leaf());
+        assert_eq!(pte.get_flags().to_string(), "vrw--ad");
+
+        pte.clear_accessed();
+        pte.clear_dirty();
+        assert!(!pte.accessed() && !pte.dirty());
+        assert_eq!(pte.rsw(), RSW::COWPage);
+        assert_eq!(PageTableEntry::from(u64::from(pte)), pte);
+
+        // Valid without R, W or X points to the next table
+        let mut table = PageTableEntry::from(0);
+        table.set_mapping(0x8000_0000);
+        table.set_valid(true);
+        assert!(!table.is_leaf());
+        assert_eq!(table.get_flags().to_string(), "v------");
+    }
+
+    #[test]
+    fn page_rounding() {
+        assert_eq!(PGROUNDUP!(0), 0);
+        assert_eq!(PGROUNDUP!(1), PAGE_SIZE);
+        assert_eq!(PGROUNDUP!(PAGE_SIZE), PAGE_SIZE);
+        assert_eq!(PGROUNDUP!(PAGE_SIZE + 1), 2 * PAGE_SIZE);
+        assert_eq!(PGROUNDDOWN!(PAGE_SIZE - 1), 0);
+        assert_eq!(PGROUNDDOWN!(2 * PAGE_SIZE + 5), 2 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn copies_from_user_pages_only() {
+        let arena = Arena::new();
+        let mut page_table = page_table(&arena);
+        let first = (&arena).alloc_page().unwrap();
+        let second = (&arena).alloc_page().unwrap();
+        unsafe {
+            (&arena)
+                .page(first)
+                .add(PAGE_SIZE - 3)
+                .copy_from(b"abc".as_ptr(), 3);
+            (&arena).page(second).copy_from(b"def".as_ptr(), 3);
+        }
+        let user = PageTableEntryFlags::R | PageTableEntryFlags::U;
+        page_table
+            .map_pages(0x1000_0000, PAGE_SIZE, first, user)
+            .unwrap();
+        page_table
+            .map_pages(0x1000_1000, PAGE_SIZE, second, user)
+            .unwrap();
+        page_table
+            .map_pages(0x1000_2000, PAGE_SIZE, second, PageTableEntryFlags::R)
+            .unwrap();
+
+        // Across the boundary between two pages that are not contiguous in physical memory
+        let mut bytes = [0; 6];
+        assert_eq!(page_table.copy_from_user(&mut bytes, 0x1000_0ffd), Ok(()));
+        assert_eq!(&bytes, b"abcdef");
+        // Into a kernel page, an unmapped page, or past the end of the address space
+        assert_eq!(
+            page_table.copy_from_user(&mut bytes, 0x1000_1ffd),
+            Err(BadUserAddress)
+        );
+        assert_eq!(
+            page_table.copy_from_user(&mut bytes, 0x2000_0000),
+            Err(BadUserAddress)
+        );
+        assert_eq!(
+            page_table.copy_from_user(&mut bytes, usize::MAX - 2),
+            Err(BadUserAddress)
+        );
+    }
+
+    #[test]
+    fn copies_to_writable_user_pages_only() {
+        let arena = Arena::new();
+        let mut page_table = page_table(&arena);
+        let writable = (&arena).alloc_page().unwrap();
+        let read_only = (&arena).alloc_page().unwrap();
+        page_table
+            .map_pages(
+                0x1000_0000,
+                PAGE_SIZE,
+                writable,
+                PageTableEntryFlags::RW | PageTableEntryFlags::U,
+            )
+            .unwrap();
+        page_table
+            .map_pages(
+                0x1000_1000,
+                PAGE_SIZE,
+                read_only,
+                PageTableEntryFlags::R | PageTableEntryFlags::U,
+            )
+            .unwrap();
+
+        assert_eq!(page_table.copy_to_user(0x1000_0010, b"dmesg"), Ok(()));
+        let mut bytes = [0; 5];
+        assert_eq!(page_table.copy_from_user(&mut bytes, 0x1000_0010), Ok(()));
+        assert_eq!(&bytes, b"dmesg");
+        // Written as a user store would have, so a shared file mapping writes the page back
+        let flags = page_table
+            .walk_const(0x1000_0010, |pte, _| pte.get_flags())
+            .unwrap();
+        assert!(flags.contains(PageTableEntryFlags::A | PageTableEntryFlags::D));
+        // Reading doesn't dirty a page
+        assert_eq!(page_table.copy_from_user(&mut bytes, 0x1000_1000), Ok(()));
+        assert!(!page_table
+            .walk_const(0x1000_1000, |pte, _| pte.dirty())
+            .unwrap());
+        assert_eq!(
+            page_table.copy_to_user(0x1000_0ffe, b"dmesg"),
+            Err(BadUserAddress)
+        );
+    }
+
+    #[test]
+    fn maps_and_translates_pages() {
+        let arena = Arena::new();
+        let mut page_table = page_table(&arena);
+        page_table
+            .map_pages(
+                0x1000_0800,
+                2 * PAGE_SIZE,
+                0x9000_0000,
+                PageTableEntryFlags::RW,
+            )
+            .unwrap();
+        // The region is not page aligned, so it spans three pages
+        assert_eq!(page_table.translate(0x1000_0123), Some(0x9000_0123));
+        assert_eq!(page_table.translate(0x1000_2fff), Some(0x9000_2fff));
+        assert_eq!(page_table.translate(0x1000_3000), None);
+        assert_eq!(page_table.translate(0x2000_0000), None);
+        assert_eq!(leaf_level(&page_table, 0x1000_1000), 0);
+        // The root, and one table at each of the two levels below it
+        assert_eq!(arena.pages_in_use(), 3);
+
+        assert!(matches!(
+            page_table.map_pages(
+                0x2000_0000,
+                PAGE_SIZE,
+                0x9000_0000,
+                PageTableEntryFlags::RW | PageTableEntryFlags::X
+            ),
+            Err(PageTableMapError::WritableAndExecutable)
+        ));
+    }
+
+    #[test]
+    fn aligned_regions_use_superpages() {
+        let arena = Arena::new();
+        let mut page_table = page_table(&arena);
+        let megapage = level_size(1);
+        page_table
+            .map_pages(
+                0x4000_0000,
+                megapage + PAGE_SIZE,
+                0x9000_0000,
+                PageTableEntryFlags::R,
+            )
+            .unwrap();
+        assert_eq!(leaf_level(&page_table, 0x4000_0000), 1);
+        assert_eq!(leaf_level(&page_table, 0x4000_0000 + megapage), 0);
+        assert_eq!(
+            page_table.translate(0x4000_0000 + megapage - 1),
+            Some(0x9000_0000 + megapage - 1)
+        );
+
+        // A physical address that is not aligned to a megapage needs small pages
+        page_table
+            .map_pages(0x8000_0000, megapage, 0x9000_1000, PageTableEntryFlags::R)
+            .unwrap();
+        assert_eq!(leaf_level(&page_table, 0x8000_0000), 0);
+    }
+
+    #[test]
+    fn unmapping_part_of_a_superpage_splits_it() {
+        let arena = Arena::new();
+        let mut page_table = page_table(&arena);
+        page_table
+            .map_pages(
+                0x4000_0000,
+                level_size(1),
+                0x9000_0000,
+                PageTableEntryFlags::RW,
+            )
+            .unwrap();
+        let pages_before = arena.pages_in_use();
+
+        let mut unmapped = Vec::new();
+        page_table
+            .unmap_pages(0x4000_5000, PAGE_SIZE, |virtual_address, pte| {
+                unmapped.push((virtual_address, pte.physical_address()));
+            })
+            .unwrap();
+        assert_eq!(unmapped, [(0x4000_5000, 0x9000_5000)]);
+        assert_eq!(arena.pages_in_use(), pages_before + 1);
+        assert_eq!(page_table.translate(0x4000_5000), None);
+        assert_eq!(page_table.translate(0x4000_4fff), Some(0x9000_4fff));
+        assert_eq!(page_table.translate(0x4000_6000), Some(0x9000_6000));
+        assert_eq!(leaf_level(&page_table, 0x4000_6000), 0);
+    }
+
+    #[test]
+    fn unmapping_hands_over_every_leaf_once_cleared() {
+        let arena = Arena::new();
+        let mut page_table = page_table(&arena);
+        let megapage = level_size(1);
+        page_table
+            .map_pages(
+                0x4000_0000,
+                megapage + 2 * PAGE_SIZE,
+                0x9000_0000,
+                PageTableEntryFlags::RW,
+            )
+            .unwrap();
+        page_table
+            .walk_mut(0x4000_0000 + megapage, 0, false, |pte, _| {
+                pte.set_flags(pte.get_flags() | PageTableEntryFlags::A | PageTableEntryFlags::D);
+            })
+            .unwrap();
+
+        let mut unmapped = Vec::new();
+        page_table
+            .unmap_pages(
+                0x4000_0000,
+                megapage + 4 * PAGE_SIZE,
+                |virtual_address, pte| {
+                    assert!(pte.is_leaf());
+                    unmapped.push((virtual_address, pte.physical_address(), pte.dirty()));
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            unmapped,
+            [
+                (0x4000_0000, 0x9000_0000, false),
+                (0x4000_0000 + megapage, 0x9000_0000 + megapage, true),
+                (0x4000_1000 + megapage, 0x9000_1000 + megapage, false),
+            ]
+        );
+        assert_eq!(page_table.translate(0x4000_0000), None);
+        // The table for the small pages is kept, with nothing left in their entries
+        let entry = page_table
+            .walk_const(0x4000_0000 + megapage, |pte, _| pte.0)
+            .unwrap();
+        assert_eq!(entry, 0);
+    }
+
+    #[test]
+    fn protecting_keeps_accessed_and_dirty() {
+        let arena = Arena::new();
+        let mut page_table = page_table(&arena);
+        page_table
+            .map_pages(0x1000_0000, PAGE_SIZE, 0x9000_0000, PageTableEntryFlags::RW)
+            .unwrap();
+        page_table
+            .walk_mut(0x1000_0000, 0, false, |pte, _| {
+                pte.set_flags(pte.get_flags() | PageTableEntryFlags::A | PageTableEntryFlags::D);
+            })
+            .unwrap();
+        page_table
+            .protect_pages(0x1000_0000, PAGE_SIZE, PageTableEntryFlags::R)
+            .unwrap();
+        let flags = page_table
+            .walk_const(0x1000_0000, |pte, _| pte.get_flags())
+            .unwrap();
+        assert_eq!(flags.to_string(), "vr---ad");
+    }
+
+    #[test]
+    fn dropping_frees_every_table() {
+        let arena = Arena::new();
+        {
+            let mut page_table = page_table(&arena);
+            for gigapage in 0..4 {
+                page_table
+                    .map_pages(
+                        gigapage * level_size(2) + PAGE_SIZE,
+                        PAGE_SIZE,
+                        0x9000_0000,
+                        PageTableEntryFlags::R,
+                    )
+                    .unwrap();
+            }
+            assert_eq!(arena.pages_in_use(), 1 + 4 * 2);
+        }
+        assert_eq!(arena.pages_in_use(), 0);
+    }
+
+    #[test]
+    fn running_out_of_table_pages_is_an_error() {
+        let arena = Arena::new();
+        let mut page_table = page_table(&arena);
+        let mut mapped = 0;
+        let error = loop {
+            // Each page is in its own gigapage, so needs two more table pages
+            match page_table.map_pages(
+                mapped * level_size(2),
+                PAGE_SIZE,
+                0x9000_0000,
+                PageTableEntryFlags::R,
+            ) {
+                Ok(()) => mapped += 1,
+                Err(error) => break error,
+            }
+        };
+        assert!(matches!(error, PageTableMapError::OutOfMemory));
+        assert_eq!(mapped, (ARENA_PAGES - 1) / 2);
+    }
+}
+
+#[cfg(all(test, not(host)))]
+mod kernel_tests {
+    use super::*;
+
+    #[test_case]
+    fn kernel_page_table_is_active() {
+        let page_table = KERNEL_PAGE_TABLE.get().unwrap();
+        assert_eq!(satp::read().bits(), page_table.satp());
+    }
+
+    #[test_case]
+    fn kernel_is_mapped_at_its_physical_addresses() {
+        let page_table = KERNEL_PAGE_TABLE.get().unwrap();
+        for address in [
+            crate::_start as usize,
+            crate::etext as usize,
+            crate::end as usize,
+        ] {
+            assert_eq!(page_table.translate(address), Some(address));
+        }
+        assert_eq!(
+            page_table.translate(trampoline_address()),
+            Some(crate::trampoline as usize)
+        );
+    }
+
+    #[test_case]
+    fn kernel_text_is_not_writable() {
+        let page_table = KERNEL_PAGE_TABLE.get().unwrap();
+        let flags = page_table
+            .walk_const(crate::_start as usize, |pte, _| pte.get_flags())
+            .unwrap();
+        assert!(flags.contains(PageTableEntryFlags::RX));
+        assert!(!flags.contains(PageTableEntryFlags::W));
+    }
+}
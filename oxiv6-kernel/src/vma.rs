@@ -0,0 +1,448 @@
+/*
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+use crate::file::{File, FileError};
+use crate::vm::{
+    trampoline_address, PageTable, PageTableEntry, PageTableEntryFlags, PageTableMapError,
+    PAGE_SIZE, PGROUNDUP,
+};
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::{sync::Arc, vec::Vec};
+use bitflags::bitflags;
+
+/// One beyond the highest address handed out by `mmap`, leaving room for the trapframe below the trampoline
+fn mmap_ceiling() -> usize {
+    trampoline_address() - PAGE_SIZE
+}
+
+bitflags! {
+    /// Access permissions of a mapping, with the same values as `PROT_*`
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub(crate) struct Protection: u8 {
+        const READ = 1;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+    }
+}
+
+bitflags! {
+    /// Flags controlling how a mapping is created, with the same values as `MAP_*`
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub(crate) struct MapFlags: u32 {
+        const SHARED = 1;
+        const PRIVATE = 1 << 1;
+        const FIXED = 1 << 4;
+        const ANONYMOUS = 1 << 5;
+    }
+}
+
+impl From<Protection> for PageTableEntryFlags {
+    /// User accessible flags for a protection, write access implies read access as Sv39 reserves W without R
+    /// `PROT_NONE` pages stay mapped, but only for the kernel, so their contents survive a later `mprotect`
+    fn from(value: Protection) -> Self {
+        if value.is_empty() {
+            return PageTableEntryFlags::R;
+        }
+        let mut flags = PageTableEntryFlags::U;
+        if value.intersects(Protection::READ | Protection::WRITE) {
+            flags |= PageTableEntryFlags::R;
+        }
+        if value.contains(Protection::WRITE) {
+            flags |= PageTableEntryFlags::W;
+        }
+        if value.contains(Protection::EXEC) {
+            flags |= PageTableEntryFlags::X;
+        }
+        flags
+    }
+}
+
+/// What fills the pages of a mapping
+#[derive(Debug, Clone)]
+pub(crate) enum Backing {
+    /// Zero filled memory
+    Anonymous,
+    /// The contents of `file`, starting at the page aligned `offset`
+    File { file: Arc<dyn File>, offset: usize },
+}
+
+/// A contiguous, page aligned region of a process's address space created by `mmap`
+#[derive(Debug, Clone)]
+pub(crate) struct VirtualMemoryArea {
+    start: usize,
+    end: usize,
+    protection: Protection,
+    flags: MapFlags,
+    backing: Backing,
+}
+
+impl VirtualMemoryArea {
+    /// Is this a file mapping whose changes must be written back to the file?
+    fn writes_back(&self) -> bool {
+        self.flags.contains(MapFlags::SHARED) && matches!(self.backing, Backing::File { .. })
+    }
+
+    /// Offset into the backing file of the page at `virtual_address`
+    fn file_offset(&self, virtual_address: usize) -> usize {
+        match &self.backing {
+            Backing::Anonymous => 0,
+            Backing::File { offset, .. } => offset + (virtual_address - self.start),
+        }
+    }
+
+    /// The part of this area starting at `virtual_address`, keeping the file offsets lined up
+    fn split_off(&mut self, virtual_address: usize) -> VirtualMemoryArea {
+        let mut upper = self.clone();
+        upper.start = virtual_address;
+        if let Backing::File { offset, .. } = &mut upper.backing {
+            *offset = self.file_offset(virtual_address);
+        }
+        self.end = virtual_address;
+        upper
+    }
+
+    /// Allocate, fill and map the page at `virtual_address`
+    fn populate_page(
+        &self,
+        page_table: &mut PageTable<'_>,
+        virtual_address: usize,
+    ) -> Result<(), MmapError> {
+        let layout = page_layout();
+        let page = unsafe { alloc_zeroed(layout) };
+        if page.is_null() {
+            crate::proc::oom_kill();
+            return Err(MmapError::OutOfMemory);
+        }
+        if let Backing::File { file, .. } = &self.backing {
+            let page_data = unsafe { core::slice::from_raw_parts_mut(page, PAGE_SIZE) };
+            if let Err(error) = file.read_at(self.file_offset(virtual_address), page_data) {
+                unsafe { dealloc(page, layout) };
+                return Err(error.into());
+            }
+        }
+        page_table
+            .map_pages(
+                virtual_address,
+                PAGE_SIZE,
+                page as usize,
+                self.protection.into(),
+            )
+            .map_err(|error| {
+                unsafe { dealloc(page, layout) };
+                error.into()
+            })
+    }
+
+    /// Unmap and free every page of this area, writing dirty pages of shared file mappings back first
+    fn release(&self, page_table: &mut PageTable<'_>) {
+        page_table
+            .unmap_pages(self.start, self.end - self.start, |virtual_address, pte| {
+                if self.writes_back() && pte.dirty() {
+                    self.write_back_page(virtual_address, pte);
+                }
+                unsafe { dealloc(pte.pa_mut::<u8>().as_mut_ptr(), page_layout()) };
+            })
+            .expect("Areas are mapped a page at a time, so there are no superpages to split");
+    }
+
+    /// Write the page mapped by `pte` back to the file, without growing the file
+    fn write_back_page(&self, virtual_address: usize, pte: PageTableEntry) {
+        if let Backing::File { file, .. } = &self.backing {
+            let offset = self.file_offset(virtual_address);
+            let length = core::cmp::min(PAGE_SIZE, file.size().saturating_sub(offset));
+            if length != 0 {
+                if let Err(error) = file.write_at(offset, &pte.pa_const::<u8>()[..length]) {
+                    log::warn!(
+                        "Unable to write back mapped page at 0x{:x}: {:?}",
+                        virtual_address,
+                        error
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The memory mapped regions of a process, sorted by address and never overlapping
+#[derive(Debug, Default)]
+pub(crate) struct VirtualMemoryAreas {
+    areas: Vec<VirtualMemoryArea>,
+}
+
+impl VirtualMemoryAreas {
+    /// Create a new mapping of `length` bytes in `page_table`, returning the address it was placed at
+    /// Without `MAP_FIXED`, `address` is only a hint, and the mapping is placed above `floor` (the top of the process heap).
+    /// Shared file mappings are copied into private pages and written back on unmap, there is no page cache to share them through.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn mmap(
+        &mut self,
+        page_table: &mut PageTable<'_>,
+        floor: usize,
+        address: usize,
+        length: usize,
+        protection: Protection,
+        flags: MapFlags,
+        backing: Backing,
+    ) -> Result<usize, MmapError> {
+        if length == 0
+            || length > mmap_ceiling()
+            || flags.contains(MapFlags::SHARED) == flags.contains(MapFlags::PRIVATE)
+            || flags.contains(MapFlags::ANONYMOUS) != matches!(backing, Backing::Anonymous)
+        {
+            return Err(MmapError::InvalidArgument);
+        }
+        if let Backing::File { file, offset } = &backing {
+            if !offset.is_multiple_of(PAGE_SIZE) {
+                return Err(MmapError::InvalidArgument);
+            }
+            if !file.readable() {
+                return Err(MmapError::PermissionDenied);
+            }
+        }
+        check_protection(protection, flags, &backing)?;
+
+        let length = PGROUNDUP!(length);
+        let floor = PGROUNDUP!(floor);
+        let start = if flags.contains(MapFlags::FIXED) {
+            if !address.is_multiple_of(PAGE_SIZE)
+                || address < floor
+                || address > mmap_ceiling() - length
+            {
+                return Err(MmapError::InvalidArgument);
+            }
+            self.munmap(page_table, address, length)?;
+            address
+        } else if address.is_multiple_of(PAGE_SIZE)
+            && address >= floor
+            && address <= mmap_ceiling() - length
+            && self.is_free(address, address + length)
+        {
+            address
+        } else {
+            self.find_free(floor, length)
+                .ok_or(MmapError::OutOfAddressSpace)?
+        };
+
+        let area = VirtualMemoryArea {
+            start,
+            end: start + length,
+            protection,
+            flags,
+            backing,
+        };
+        for virtual_address in (area.start..area.end).step_by(PAGE_SIZE) {
+            if let Err(error) = area.populate_page(page_table, virtual_address) {
+                area.release(page_table);
+                return Err(error);
+            }
+        }
+
+        let index = self.areas.partition_point(|other| other.start < area.start);
+        self.areas.insert(index, area);
+        Ok(start)
+    }
+
+    /// Remove any mappings in `[address, address + length)`, splitting areas that are only partially covered
+    pub(crate) fn munmap(
+        &mut self,
+        page_table: &mut PageTable<'_>,
+        address: usize,
+        length: usize,
+    ) -> Result<(), MmapError> {
+        if !address.is_multiple_of(PAGE_SIZE) || length == 0 {
+            return Err(MmapError::InvalidArgument);
+        }
+        let end = range_end(address, length).ok_or(MmapError::InvalidArgument)?;
+
+        let mut index = 0;
+        while index < self.areas.len() {
+            let area = &mut self.areas[index];
+            if area.end <= address || area.start >= end {
+                index += 1;
+                continue;
+            }
+
+            // Carve the area into the part before, the part being removed, and the part after
+            let upper = (area.end > end).then(|| area.split_off(end));
+            let removed = if area.start < address {
+                let removed = area.split_off(address);
+                index += 1;
+                removed
+            } else {
+                self.areas.remove(index)
+            };
+            removed.release(page_table);
+            if let Some(upper) = upper {
+                self.areas.insert(index, upper);
+                index += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Change the protection of `[address, address + length)`, splitting areas that are only partially covered
+    /// Every page in the range must already be mapped
+    pub(crate) fn mprotect(
+        &mut self,
+        page_table: &mut PageTable<'_>,
+        address: usize,
+        length: usize,
+        protection: Protection,
+    ) -> Result<(), MmapError> {
+        if !address.is_multiple_of(PAGE_SIZE) || length == 0 {
+            return Err(MmapError::InvalidArgument);
+        }
+        let end = range_end(address, length)
+            .filter(|&end| end <= mmap_ceiling())
+            .ok_or(MmapError::InvalidArgument)?;
+
+        // Check the whole range before changing anything, so a failure leaves every area as it was
+        let mut covered = address;
+        for area in self
+            .areas
+            .iter()
+            .filter(|area| area.end > address && area.start < end)
+        {
+            if area.start > covered {
+                break;
+            }
+            check_protection(protection, area.flags, &area.backing)?;
+            covered = area.end;
+        }
+        if covered < end {
+            return Err(MmapError::NotMapped);
+        }
+
+        let mut index = 0;
+        while index < self.areas.len() {
+            let area = &mut self.areas[index];
+            if area.end <= address || area.start >= end {
+                index += 1;
+                continue;
+            }
+
+            // Leave the part before the range as it is, and handle the rest on the next pass
+            if area.start < address {
+                let upper = area.split_off(address);
+                self.areas.insert(index + 1, upper);
+                index += 1;
+                continue;
+            }
+            if area.end > end {
+                let upper = area.split_off(end);
+                self.areas.insert(index + 1, upper);
+            }
+            let area = &mut self.areas[index];
+            area.protection = protection;
+            page_table.protect_pages(area.start, area.end - area.start, protection.into())?;
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Unmap every area, writing back shared file mappings. Called when a process exits
+    pub(crate) fn unmap_all(&mut self, page_table: &mut PageTable<'_>) {
+        for area in self.areas.drain(..) {
+            area.release(page_table);
+        }
+    }
+
+    /// The bytes mapped across every area, all of which are populated
+    pub(crate) fn mapped_bytes(&self) -> usize {
+        self.areas.iter().map(|area| area.end - area.start).sum()
+    }
+
+    /// Is `[start, end)` free of any existing area?
+    fn is_free(&self, start: usize, end: usize) -> bool {
+        self.areas
+            .iter()
+            .all(|area| area.end <= start || area.start >= end)
+    }
+
+    /// Find the highest gap of `length` bytes between `floor` and [`mmap_ceiling`]
+    fn find_free(&self, floor: usize, length: usize) -> Option<usize> {
+        let mut ceiling = mmap_ceiling();
+        for area in self.areas.iter().rev() {
+            if area.end <= ceiling && ceiling - area.end >= length {
+                return Some(ceiling - length).filter(|start| *start >= floor);
+            }
+            ceiling = core::cmp::min(ceiling, area.start);
+        }
+        ceiling.checked_sub(length).filter(|start| *start >= floor)
+    }
+}
+
+/// The page-aligned end of `[address, address + length)`, or `None` if it would pass the top of the address space
+fn range_end(address: usize, length: usize) -> Option<usize> {
+    address
+        .checked_add(length)?
+        .checked_next_multiple_of(PAGE_SIZE)
+}
+
+/// Can an area with `flags` and `backing` be given `protection`?
+/// Nothing may be both writable and executable, and shared file mappings may only be written if the file can be
+fn check_protection(
+    protection: Protection,
+    flags: MapFlags,
+    backing: &Backing,
+) -> Result<(), MmapError> {
+    if protection.contains(Protection::WRITE | Protection::EXEC) {
+        return Err(MmapError::PermissionDenied);
+    }
+    if let Backing::File { file, .. } = backing {
+        if flags.contains(MapFlags::SHARED)
+            && protection.contains(Protection::WRITE)
+            && !file.writeable()
+        {
+            return Err(MmapError::PermissionDenied);
+        }
+    }
+    Ok(())
+}
+
+#[inline]
+fn page_layout() -> Layout {
+    unsafe { Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE) }
+}
+
+#[derive(Debug)]
+pub(crate) enum MmapError {
+    InvalidArgument,
+    PermissionDenied,
+    OutOfAddressSpace,
+    OutOfMemory,
+    /// Part of the range is not mapped
+    NotMapped,
+    #[allow(dead_code)]
+    File(FileError),
+    #[allow(dead_code)]
+    PageTableMapError(PageTableMapError),
+}
+
+impl From<FileError> for MmapError {
+    fn from(value: FileError) -> Self {
+        Self::File(value)
+    }
+}
+
+impl From<PageTableMapError> for MmapError {
+    fn from(value: PageTableMapError) -> Self {
+        match value {
+            PageTableMapError::OutOfMemory => Self::OutOfMemory,
+            value => Self::PageTableMapError(value),
+        }
+    }
+}
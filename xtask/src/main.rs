@@ -0,0 +1,249 @@
+/*!
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! Build oxiv6 and boot it in QEMU, to run it, run its tests, or debug it with gdb
+
+mod symbols;
+
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode, ExitStatus, Stdio};
+
+const USAGE: &str = "\
+Usage: cargo xtask <task> [options] [-- kernel arguments...]
+
+Tasks:
+    run     Build the kernel and boot it
+    test    Build the kernel's tests and boot them, failing if any test fails
+    gdb     Build the kernel and boot it stopped, waiting for gdb to attach
+    help    Print this message
+
+Options:
+    --release           Build with the release profile
+    --features <list>   Kernel features to enable, separated by commas
+    --cpus <count>      Harts to give the machine [default: 1]
+    --memory <size>     Memory to give the machine, in QEMU's -m syntax [default: 128M]
+    --disk <image>      A raw disk image to attach as a virtio block device
+    --gdb-port <port>   The port gdb attaches to [default: 1234]
+
+Kernel arguments are passed to the kernel in /chosen/bootargs, such as `loglevel=debug`.
+QEMU is run as `qemu-system-riscv64`, unless the QEMU environment variable names another binary.";
+
+/// What to do with the kernel once it is built
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Task {
+    Run,
+    Test,
+    Gdb,
+}
+
+#[derive(Debug)]
+struct Options {
+    release: bool,
+    features: Vec<String>,
+    cpus: usize,
+    memory: String,
+    disk: Option<PathBuf>,
+    gdb_port: u16,
+    kernel_arguments: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            release: false,
+            features: Vec::new(),
+            cpus: 1,
+            memory: String::from("128M"),
+            disk: None,
+            gdb_port: 1234,
+            kernel_arguments: Vec::new(),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    match try_main() {
+        Ok(exit_code) => exit_code,
+        Err(message) => {
+            eprintln!("xtask: {message}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn try_main() -> Result<ExitCode, String> {
+    let mut arguments = env::args().skip(1);
+    let task = match arguments.next().as_deref() {
+        Some("run") => Task::Run,
+        Some("test") => Task::Test,
+        Some("gdb") => Task::Gdb,
+        Some("help" | "--help" | "-h") | None => {
+            println!("{USAGE}");
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(task) => return Err(format!("unknown task `{task}`\n\n{USAGE}")),
+    };
+    let options = parse_options(arguments)?;
+
+    let kernel = build_kernel(task, &options)?;
+    if let Err(message) = symbols::embed(&kernel) {
+        eprintln!("xtask: warning: {message}, so backtraces will not name functions");
+    }
+    let mut qemu = qemu(&kernel, &options);
+    if task == Task::Gdb {
+        qemu.arg("-S")
+            .args(["-gdb", &format!("tcp::{}", options.gdb_port)]);
+        eprintln!(
+            "xtask: waiting for gdb, attach with\n    gdb-multiarch {} -ex 'target remote :{}'",
+            kernel.display(),
+            options.gdb_port
+        );
+    }
+    let status = qemu.status().map_err(|error| {
+        let program = qemu.get_program().to_string_lossy();
+        format!("unable to start {program}: {error}")
+    })?;
+    kernel_exit(task, status)
+}
+
+/// Parse the options after the task, and any kernel arguments after `--`
+fn parse_options(mut arguments: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(argument) = arguments.next() {
+        let mut value = |name: &str| {
+            arguments
+                .next()
+                .ok_or_else(|| format!("{name} needs a value"))
+        };
+        match argument.as_str() {
+            "--release" => options.release = true,
+            "--features" => options.features.push(value("--features")?),
+            "--cpus" => {
+                options.cpus = value("--cpus")?
+                    .parse()
+                    .ok()
+                    .filter(|&cpus| cpus > 0)
+                    .ok_or("--cpus must be a positive number")?;
+            }
+            "--memory" => options.memory = value("--memory")?,
+            "--disk" => options.disk = Some(PathBuf::from(value("--disk")?)),
+            "--gdb-port" => {
+                options.gdb_port = value("--gdb-port")?
+                    .parse()
+                    .map_err(|_| "--gdb-port must be a port number")?;
+            }
+            "--" => {
+                options.kernel_arguments.extend(arguments);
+                break;
+            }
+            _ => return Err(format!("unknown option `{argument}`\n\n{USAGE}")),
+        }
+    }
+    Ok(options)
+}
+
+/// Build the kernel, or its test binary for [`Task::Test`], returning the path of the ELF to boot
+fn build_kernel(task: Task, options: &Options) -> Result<PathBuf, String> {
+    let kernel_directory = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .join("oxiv6-kernel");
+    let mut cargo = Command::new(env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo")));
+    // The kernel's own cargo config picks the target and its flags
+    cargo.current_dir(kernel_directory);
+    if task == Task::Test {
+        cargo.args(["test", "--no-run"]);
+    } else {
+        cargo.arg("build");
+    }
+    cargo.arg("--message-format=json-render-diagnostics");
+    if options.release {
+        cargo.arg("--release");
+    }
+    if !options.features.is_empty() {
+        cargo.args(["--features", &options.features.join(",")]);
+    }
+    let output = cargo
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|error| format!("unable to run cargo: {error}"))?;
+    if !output.status.success() {
+        return Err(String::from("the kernel failed to build"));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|message| {
+            message.contains(r#""reason":"compiler-artifact""#)
+                && message.contains(r#""name":"oxiv6-kernel""#)
+        })
+        .filter_map(executable)
+        .next_back()
+        .ok_or_else(|| String::from("cargo built no kernel executable"))
+}
+
+/// The `executable` of a compiler artifact message from cargo, if it has one
+fn executable(message: &str) -> Option<PathBuf> {
+    const KEY: &str = r#""executable":""#;
+    let start = message.find(KEY)? + KEY.len();
+    let length = message[start..].find('"')?;
+    Some(PathBuf::from(&message[start..start + length]))
+}
+
+/// QEMU's virt machine booting `kernel` from the default SBI firmware, with its console on stdio
+fn qemu(kernel: &Path, options: &Options) -> Command {
+    let mut qemu =
+        Command::new(env::var_os("QEMU").unwrap_or_else(|| OsString::from("qemu-system-riscv64")));
+    qemu.args(["-machine", "virt", "-bios", "default", "-nographic"])
+        .args(["-smp", &options.cpus.to_string()])
+        .args(["-m", &options.memory])
+        .arg("-kernel")
+        .arg(kernel);
+    if let Some(disk) = &options.disk {
+        let mut drive = OsString::from("file=");
+        drive.push(disk);
+        drive.push(",if=none,format=raw,id=disk0");
+        qemu.arg("-drive").arg(drive).args([
+            "-device",
+            "virtio-blk-device,drive=disk0,bus=virtio-mmio-bus.0",
+        ]);
+    }
+    if !options.kernel_arguments.is_empty() {
+        qemu.args(["-append", &options.kernel_arguments.join(" ")]);
+    }
+    qemu
+}
+
+/// Turn QEMU's exit status into ours. The kernel shuts down with `NoReason` when all is well, and resets with
+/// `SystemFailure` when it panics, which QEMU exits with as a failure
+fn kernel_exit(task: Task, status: ExitStatus) -> Result<ExitCode, String> {
+    let code = status
+        .code()
+        .ok_or_else(|| String::from("QEMU was killed by a signal"))?;
+    if code == 0 {
+        if task == Task::Test {
+            eprintln!("xtask: kernel tests passed");
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    if task == Task::Test {
+        eprintln!("xtask: kernel tests failed (QEMU exited with {code})");
+    } else {
+        eprintln!("xtask: the kernel exited with a failure (QEMU exited with {code})");
+    }
+    Ok(ExitCode::from(u8::try_from(code).unwrap_or(1)))
+}
@@ -0,0 +1,275 @@
+/*!
+   Copyright 2024 Claire Moore
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! Fill in the kernel's `.kernel_symbols` section with its function symbols, so the kernel can name the functions
+//! in its backtraces. The table's format is described in the kernel's symbols.rs
+
+use std::fs;
+use std::path::Path;
+
+/// The section the kernel leaves for its symbol table
+const SECTION: &str = ".kernel_symbols";
+/// Starts a filled in symbol table, "SYM1"
+const SYMBOL_MAGIC: u32 = 0x314d_5953;
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const STT_FUNC: u8 = 2;
+
+/// A function in the kernel
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Function {
+    address: u64,
+    size: u32,
+    name: String,
+}
+
+/// The parts of an ELF64 section header needed here
+#[derive(Debug, Clone, Copy)]
+struct Section {
+    name: u32,
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: u32,
+}
+
+/// Write the function symbols of the kernel ELF at `path` into its symbol table section, in place
+pub(crate) fn embed(path: &Path) -> Result<(), String> {
+    let mut elf =
+        fs::read(path).map_err(|error| format!("unable to read {}: {error}", path.display()))?;
+    let (offset, size, functions) = {
+        let sections = sections(&elf)?;
+        let table = find_section(&elf, &sections, SECTION)?
+            .ok_or_else(|| format!("the kernel has no {SECTION} section"))?;
+        if table.kind == SHT_NOBITS {
+            return Err(format!(
+                "the kernel's {SECTION} section takes no room in the file"
+            ));
+        }
+        (table.offset, table.size, functions(&elf, &sections)?)
+    };
+    let table = encode(&functions);
+    if table.len() > size {
+        return Err(format!(
+            "the symbol table needs {} bytes, but {SECTION} has room for {size}",
+            table.len()
+        ));
+    }
+    let space = elf
+        .get_mut(offset..offset + size)
+        .ok_or_else(|| format!("{SECTION} is past the end of the file"))?;
+    space.fill(0);
+    space[..table.len()].copy_from_slice(&table);
+    fs::write(path, &elf).map_err(|error| format!("unable to write {}: {error}", path.display()))
+}
+
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N], String> {
+    offset
+        .checked_add(N)
+        .and_then(|end| bytes.get(offset..end))
+        .map(|field| field.try_into().unwrap())
+        .ok_or_else(|| String::from("the kernel ELF is truncated"))
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, String> {
+    read(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    read(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, String> {
+    read(bytes, offset).map(u64::from_le_bytes)
+}
+
+fn read_usize(bytes: &[u8], offset: usize) -> Result<usize, String> {
+    usize::try_from(read_u64(bytes, offset)?)
+        .map_err(|_| String::from("the kernel ELF is too large"))
+}
+
+/// The section headers of a little endian ELF64 file
+fn sections(elf: &[u8]) -> Result<Vec<Section>, String> {
+    if elf.get(..6) != Some(b"\x7fELF\x02\x01") {
+        return Err(String::from("the kernel is not a little endian ELF64 file"));
+    }
+    let table = read_usize(elf, 0x28)?;
+    let entry_size = usize::from(read_u16(elf, 0x3a)?);
+    let count = usize::from(read_u16(elf, 0x3c)?);
+    (0..count)
+        .map(|index| {
+            let header = table + index * entry_size;
+            Ok(Section {
+                name: read_u32(elf, header)?,
+                kind: read_u32(elf, header + 4)?,
+                offset: read_usize(elf, header + 24)?,
+                size: read_usize(elf, header + 32)?,
+                link: read_u32(elf, header + 40)?,
+            })
+        })
+        .collect()
+}
+
+/// The contents of a section
+fn contents<'a>(elf: &'a [u8], section: &Section) -> Result<&'a [u8], String> {
+    section
+        .offset
+        .checked_add(section.size)
+        .and_then(|end| elf.get(section.offset..end))
+        .ok_or_else(|| String::from("the kernel ELF is truncated"))
+}
+
+/// The nul terminated string at `offset` in a string table
+fn string(strings: &[u8], offset: usize) -> Result<&str, String> {
+    let bytes = strings
+        .get(offset..)
+        .ok_or_else(|| String::from("a symbol name is outside its string table"))?;
+    let length = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    std::str::from_utf8(&bytes[..length]).map_err(|_| String::from("a symbol name is not UTF-8"))
+}
+
+/// The section called `name`
+fn find_section(elf: &[u8], sections: &[Section], name: &str) -> Result<Option<Section>, String> {
+    let names_index = usize::from(read_u16(elf, 0x3e)?);
+    let names = contents(
+        elf,
+        sections
+            .get(names_index)
+            .ok_or_else(|| String::from("the kernel ELF has no section names"))?,
+    )?;
+    for section in sections {
+        if string(names, section.name as usize)? == name {
+            return Ok(Some(*section));
+        }
+    }
+    Ok(None)
+}
+
+/// Every function with a size in the ELF's symbol table, demangled and sorted by address. Functions with the same
+/// address, such as those merged by the linker, keep the first name
+fn functions(elf: &[u8], sections: &[Section]) -> Result<Vec<Function>, String> {
+    let symbol_table = sections
+        .iter()
+        .find(|section| section.kind == SHT_SYMTAB)
+        .ok_or_else(|| String::from("the kernel has no symbol table, was it stripped?"))?;
+    let symbols = contents(elf, symbol_table)?;
+    let strings = contents(
+        elf,
+        sections
+            .get(symbol_table.link as usize)
+            .ok_or_else(|| String::from("the symbol table has no string table"))?,
+    )?;
+    let mut functions = Vec::new();
+    for symbol in symbols.chunks_exact(24) {
+        let size = read_u64(symbol, 16)?;
+        if symbol[4] & 0xf != STT_FUNC || size == 0 {
+            continue;
+        }
+        functions.push(Function {
+            address: read_u64(symbol, 8)?,
+            size: u32::try_from(size).unwrap_or(u32::MAX),
+            name: demangle(string(strings, read_u32(symbol, 0)? as usize)?),
+        });
+    }
+    functions.sort_by_key(|function| function.address);
+    functions.dedup_by_key(|function| function.address);
+    Ok(functions)
+}
+
+/// The symbol table, as the kernel reads it
+fn encode(functions: &[Function]) -> Vec<u8> {
+    let names_length: usize = functions.iter().map(|function| function.name.len()).sum();
+    let mut table = Vec::with_capacity(HEADER_SIZE + functions.len() * ENTRY_SIZE + names_length);
+    for field in [
+        SYMBOL_MAGIC,
+        u32_length(functions.len()),
+        u32_length(names_length),
+        0,
+    ] {
+        table.extend_from_slice(&field.to_le_bytes());
+    }
+    let mut name_offset = 0;
+    for function in functions {
+        table.extend_from_slice(&function.address.to_le_bytes());
+        table.extend_from_slice(&function.size.to_le_bytes());
+        table.extend_from_slice(&u32_length(name_offset).to_le_bytes());
+        name_offset += function.name.len();
+    }
+    for function in functions {
+        table.extend_from_slice(function.name.as_bytes());
+    }
+    table
+}
+
+/// A length in the table, which is far smaller than the space the kernel leaves for it
+fn u32_length(length: usize) -> u32 {
+    u32::try_from(length).expect("the symbol table is too large")
+}
+
+/// Demangle a Rust symbol, legacy or v0, without its hash, such as `oxiv6_kernel::trap::kerneltrap`. Anything
+/// else is returned as it is
+fn demangle(symbol: &str) -> String {
+    format!("{:#}", rustc_demangle::demangle(symbol))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demangles_rust_symbols() {
+        assert_eq!(
+            demangle("_ZN12oxiv6_kernel4trap10kerneltrap17h0123456789abcdefE"),
+            "oxiv6_kernel::trap::kerneltrap"
+        );
+        assert_eq!(
+            demangle("_RNvNtCs25fBqwfte1r_12oxiv6_kernel4trap10kerneltrap"),
+            "oxiv6_kernel::trap::kerneltrap"
+        );
+        assert_eq!(demangle("_start"), "_start");
+        assert_eq!(demangle("kernelvec"), "kernelvec");
+    }
+
+    #[test]
+    fn encodes_names_after_the_entries() {
+        let table = encode(&[
+            Function {
+                address: 0x8020_0000,
+                size: 0x40,
+                name: String::from("_start"),
+            },
+            Function {
+                address: 0x8020_0100,
+                size: 0x20,
+                name: String::from("main"),
+            },
+        ]);
+        assert_eq!(table.len(), HEADER_SIZE + 2 * ENTRY_SIZE + 10);
+        assert_eq!(read_u32(&table, 0), Ok(SYMBOL_MAGIC));
+        assert_eq!(read_u32(&table, 4), Ok(2));
+        assert_eq!(read_u32(&table, 8), Ok(10));
+        assert_eq!(read_u64(&table, HEADER_SIZE + ENTRY_SIZE), Ok(0x8020_0100));
+        assert_eq!(read_u32(&table, HEADER_SIZE + ENTRY_SIZE + 8), Ok(0x20));
+        assert_eq!(read_u32(&table, HEADER_SIZE + ENTRY_SIZE + 12), Ok(6));
+        assert_eq!(&table[HEADER_SIZE + 2 * ENTRY_SIZE..], b"_startmain");
+    }
+}